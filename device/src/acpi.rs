@@ -0,0 +1,258 @@
+#![allow(dead_code)]
+
+// ACPI table discovery, only as far as needed to find the interrupt controllers.
+// follow https://wiki.osdev.org/RSDP and https://wiki.osdev.org/MADT
+//
+// Every physical range is identity mapped with `memory::map_mmio` before it is
+// touched.
+
+use core::mem::size_of;
+use core::{ptr, slice};
+use memory;
+
+pub const MAX_CPUS: usize     = 16;
+pub const MAX_IO_APICS: usize = 4;
+pub const ISA_IRQS: usize     = 16;
+
+// The RSDP lives on a 16 byte boundary in the main BIOS area below 1 MiB.
+const BIOS_AREA_START: usize = 0xE0000;
+const BIOS_AREA_SIZE: usize  = 0x20000;
+
+// Root System Description Pointer
+#[repr(C, packed)]
+struct Rsdp {
+    signature:         [u8; 8],  // "RSD PTR "
+    checksum:          u8,
+    oem_id:            [u8; 6],
+    revision:          u8,       // 0 for ACPI 1.0, 2 for ACPI 2.0+
+    rsdt_address:      u32,
+    // ACPI 2.0+
+    length:            u32,
+    xsdt_address:      u64,
+    extended_checksum: u8,
+    reserved:          [u8; 3],
+}
+
+// System Description Table header shared by every table
+#[repr(C, packed)]
+struct SdtHeader {
+    signature:        [u8; 4],
+    length:           u32,      // including the header
+    revision:         u8,
+    checksum:         u8,
+    oem_id:           [u8; 6],
+    oem_table_id:     [u8; 8],
+    oem_revision:     u32,
+    creator_id:       u32,
+    creator_revision: u32,
+}
+
+#[repr(C, packed)]
+struct MadtHeader {
+    header:             SdtHeader,
+    local_apic_address: u32,
+    flags:              u32,
+}
+
+// MADT entry type 0
+#[repr(C, packed)]
+struct LocalApicEntry {
+    kind:         u8,
+    length:       u8,
+    processor_id: u8,
+    apic_id:      u8,
+    flags:        u32,          // bit 0: processor enabled
+}
+
+// MADT entry type 1
+#[repr(C, packed)]
+struct IoApicEntry {
+    kind:     u8,
+    length:   u8,
+    id:       u8,
+    reserved: u8,
+    address:  u32,
+    gsi_base: u32,
+}
+
+// MADT entry type 2
+#[repr(C, packed)]
+struct OverrideEntry {
+    kind:   u8,
+    length: u8,
+    bus:    u8,                 // always 0 (ISA)
+    source: u8,                 // ISA IRQ
+    gsi:    u32,
+    flags:  u16,
+}
+
+// MADT entry type 5
+#[repr(C, packed)]
+struct LocalApicOverrideEntry {
+    kind:     u8,
+    length:   u8,
+    reserved: u16,
+    address:  u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id:       u8,
+    pub address:  usize,
+    pub gsi_base: u32,
+}
+
+// How an ISA IRQ is wired to the I/O APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqRoute {
+    pub gsi:         u32,
+    pub active_low:  bool,
+    pub level:       bool,
+}
+
+// What we learned from the Multiple APIC Description Table.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: usize,
+    pub local_apics:        [Option<u8>; MAX_CPUS],
+    pub io_apics:           [Option<IoApicInfo>; MAX_IO_APICS],
+    overrides:              [Option<IrqRoute>; ISA_IRQS],
+}
+
+impl Madt {
+    // ISA IRQs are identity mapped to GSIs, active high and edge triggered,
+    // unless an interrupt source override says otherwise.
+    pub fn isa_route(&self, irq: u8) -> IrqRoute {
+        self.overrides[irq as usize].unwrap_or(IrqRoute {
+            gsi: irq as u32,
+            active_low: false,
+            level: false,
+        })
+    }
+}
+
+fn checksum(address: usize, length: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+unsafe fn find_rsdp() -> Option<&'static Rsdp> {
+    memory::map_mmio(BIOS_AREA_START, BIOS_AREA_SIZE).ok()?;
+    let mut address = BIOS_AREA_START;
+    while address < BIOS_AREA_START + BIOS_AREA_SIZE {
+        let rsdp = &*(address as *const Rsdp);
+        if rsdp.signature == *b"RSD PTR " && checksum(address, 20) {
+            return Some(rsdp);
+        }
+        address += 16;
+    }
+    None
+}
+
+unsafe fn map_table(address: usize) -> Option<&'static SdtHeader> {
+    memory::map_mmio(address, size_of::<SdtHeader>()).ok()?;
+    let header = &*(address as *const SdtHeader);
+    let length = header.length as usize;
+    memory::map_mmio(address, length).ok()?;
+    if checksum(address, length) {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+// Walk the RSDT (ACPI 1.0) or XSDT (ACPI 2.0+) looking for `signature`.
+unsafe fn find_table(rsdp: &Rsdp, signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let (root_address, entry_size) = if rsdp.revision >= 2 {
+        (rsdp.xsdt_address as usize, 8)
+    } else {
+        (rsdp.rsdt_address as usize, 4)
+    };
+    let root = map_table(root_address)?;
+    let entries = root_address + size_of::<SdtHeader>();
+    let count = (root.length as usize - size_of::<SdtHeader>()) / entry_size;
+
+    for i in 0..count {
+        let address = if entry_size == 8 {
+            ptr::read_unaligned((entries + i * 8) as *const u64) as usize
+        } else {
+            ptr::read_unaligned((entries + i * 4) as *const u32) as usize
+        };
+        if let Some(table) = map_table(address) {
+            if table.signature == *signature {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+unsafe fn parse_madt(header: &'static SdtHeader) -> Madt {
+    let start = header as *const _ as usize;
+    let end = start + header.length as usize;
+    let madt_header = &*(start as *const MadtHeader);
+
+    let mut madt = Madt {
+        local_apic_address: madt_header.local_apic_address as usize,
+        local_apics: [None; MAX_CPUS],
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; ISA_IRQS],
+    };
+    let (mut ncpus, mut nioapics) = (0, 0);
+
+    let mut offset = start + size_of::<MadtHeader>();
+    while offset + 2 <= end {
+        let kind = *(offset as *const u8);
+        let length = *((offset + 1) as *const u8) as usize;
+        if length < 2 {
+            break;    // malformed entry
+        }
+        match kind {
+            0 => {
+                let entry = &*(offset as *const LocalApicEntry);
+                if entry.flags & 1 != 0 && ncpus < MAX_CPUS {
+                    madt.local_apics[ncpus] = Some(entry.apic_id);
+                    ncpus += 1;
+                }
+            }
+            1 => {
+                let entry = &*(offset as *const IoApicEntry);
+                if nioapics < MAX_IO_APICS {
+                    madt.io_apics[nioapics] = Some(IoApicInfo {
+                        id: entry.id,
+                        address: entry.address as usize,
+                        gsi_base: entry.gsi_base,
+                    });
+                    nioapics += 1;
+                }
+            }
+            2 => {
+                let entry = &*(offset as *const OverrideEntry);
+                if (entry.source as usize) < ISA_IRQS {
+                    // polarity is bits 0..1, trigger mode bits 2..3; 0b11 means low / level
+                    madt.overrides[entry.source as usize] = Some(IrqRoute {
+                        gsi: entry.gsi,
+                        active_low: entry.flags & 0b11 == 0b11,
+                        level: (entry.flags >> 2) & 0b11 == 0b11,
+                    });
+                }
+            }
+            5 => {
+                let entry = &*(offset as *const LocalApicOverrideEntry);
+                madt.local_apic_address = entry.address as usize;
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    madt
+}
+
+// Locate and parse the MADT. Returns None if the firmware provides no ACPI tables.
+pub fn init() -> Option<Madt> {
+    unsafe {
+        let rsdp = find_rsdp()?;
+        let madt = find_table(rsdp, b"APIC")?;
+        Some(parse_madt(madt))
+    }
+}
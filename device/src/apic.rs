@@ -0,0 +1,254 @@
+// Local APIC and I/O APIC
+// follow https://wiki.osdev.org/APIC and https://wiki.osdev.org/IOAPIC
+//
+// Once enabled, the 8259 pair is masked, legacy IRQs are delivered through the
+// I/O APIC on the same vectors the remapped PIC used (0x20 + IRQ), and the
+// local APIC timer replaces the PIT on IRQ0.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use sync::IrqMutex;
+use memory;
+use acpi::{Madt, IoApicInfo, ISA_IRQS};
+use io::{rdmsr, wrmsr};
+use {pic, pit};
+
+// Legacy IRQs keep the vectors assigned by `pic::remap`.
+const IRQ_BASE: u8 = 0x20;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers, offsets from its MMIO base
+const LAPIC_ID: usize            = 0x020;
+const LAPIC_TPR: usize           = 0x080;    // Task Priority Register
+const LAPIC_EOI: usize           = 0x0B0;
+const LAPIC_SVR: usize           = 0x0F0;    // Spurious interrupt Vector Register
+const LAPIC_LVT_TIMER: usize     = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize  = 0x3E0;
+
+const SVR_ENABLE: u32         = 1 << 8;
+const LVT_MASKED: u32         = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+// I/O APIC registers, accessed indirectly through IOREGSEL/IOWIN
+const IOREGSEL: usize   = 0x00;
+const IOWIN: usize      = 0x10;
+const IOAPICVER: u32    = 0x01;
+const IOREDTBL: u32     = 0x10;    // two 32 bit registers per redirection entry
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64      = 1 << 15;
+const REDIRECTION_MASKED: u64     = 1 << 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+// Where each ISA IRQ ended up, so single lines can be (un)masked later.
+#[derive(Clone, Copy)]
+struct Route {
+    ioapic_base: usize,
+    index:       u32,
+    entry:       u64,
+}
+
+static ROUTES: IrqMutex<[Option<Route>; ISA_IRQS]> = IrqMutex::new([None; ISA_IRQS]);
+
+pub struct LocalApic {
+    base: usize,
+}
+
+impl LocalApic {
+
+    unsafe fn read(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.base + reg) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.base + reg) as *mut u32, value);
+    }
+
+    pub fn id(&self) -> u8 {
+        (unsafe { self.read(LAPIC_ID) } >> 24) as u8
+    }
+
+    pub fn eoi(&self) {
+        unsafe { self.write(LAPIC_EOI, 0) };
+    }
+
+    fn enable(&self) {
+        unsafe {
+            wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE);
+            self.write(LAPIC_TPR, 0);    // accept every priority class
+            self.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        }
+    }
+
+    // Count how many timer ticks (with divider 16) pass in 10 ms of PIT time.
+    fn calibrate_timer(&self) -> u32 {
+        unsafe {
+            self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+            self.write(LAPIC_TIMER_INITIAL, 0xFFFF_FFFF);
+            pit::sleep_ms(10);
+            let elapsed = 0xFFFF_FFFF - self.read(LAPIC_TIMER_CURRENT);
+            self.write(LAPIC_TIMER_INITIAL, 0);
+            elapsed / 10
+        }
+    }
+
+    fn start_timer(&self, vector: u8, frequency: u32) {
+        let ticks_per_ms = self.calibrate_timer();
+        unsafe {
+            self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(LAPIC_LVT_TIMER, vector as u32 | LVT_TIMER_PERIODIC);
+            self.write(LAPIC_TIMER_INITIAL, ticks_per_ms * 1000 / frequency);
+        }
+    }
+}
+
+pub struct IoApic {
+    base:     usize,
+    gsi_base: u32,
+}
+
+impl IoApic {
+
+    fn new(info: &IoApicInfo) -> Self {
+        IoApic { base: info.address, gsi_base: info.gsi_base }
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    // Number of redirection entries, i.e. how many GSIs this I/O APIC handles.
+    fn count(&self) -> u32 {
+        ((unsafe { self.read(IOAPICVER) } >> 16) & 0xFF) + 1
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.count()
+    }
+
+    fn set_redirection(&self, index: u32, entry: u64) {
+        unsafe {
+            self.write(IOREDTBL + index * 2, entry as u32);
+            self.write(IOREDTBL + index * 2 + 1, (entry >> 32) as u32);
+        }
+    }
+
+    fn mask_all(&self) {
+        for index in 0..self.count() {
+            self.set_redirection(index, REDIRECTION_MASKED);
+        }
+    }
+}
+
+fn local_apic() -> LocalApic {
+    LocalApic { base: LAPIC_BASE.load(Ordering::SeqCst) }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn eoi() {
+    local_apic().eoi();
+}
+
+pub fn id() -> u8 {
+    local_apic().id()
+}
+
+fn set_irq_masked(irq: u8, masked: bool) {
+    let mut routes = ROUTES.lock();
+    if let Some(ref mut route) = routes[irq as usize] {
+        if masked {
+            route.entry |= REDIRECTION_MASKED;
+        } else {
+            route.entry &= !REDIRECTION_MASKED;
+        }
+        let ioapic = IoApic { base: route.ioapic_base, gsi_base: 0 };
+        ioapic.set_redirection(route.index, route.entry);
+    }
+}
+
+pub fn mask_irq(irq: u8) {
+    set_irq_masked(irq, true);
+}
+
+pub fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+}
+
+// Switch from the 8259 to the APICs described by `madt`.
+pub fn init(madt: &Madt) {
+    memory::map_mmio(madt.local_apic_address, 4096).expect("cannot map the local APIC");
+    for info in madt.io_apics.iter().filter_map(|info| info.as_ref()) {
+        memory::map_mmio(info.address, 4096).expect("cannot map an I/O APIC");
+    }
+
+    // interrupts stay disabled while the lock is held, no handler runs
+    // before it knows which controller to acknowledge
+    let mut routes = ROUTES.lock();
+    let lapic = LocalApic { base: madt.local_apic_address };
+    lapic.enable();
+
+    // lines masked on the 8259 stay masked on the I/O APIC
+    let pic_mask = pic::get_mask();
+    pic::disable();
+
+    for info in madt.io_apics.iter().filter_map(|info| info.as_ref()) {
+        IoApic::new(info).mask_all();
+    }
+
+    for irq in 1..ISA_IRQS as u8 {
+        if irq == 2 {
+            continue;    // the 8259 cascade, never raised
+        }
+        let route = madt.isa_route(irq);
+        let ioapic = madt.io_apics.iter()
+            .filter_map(|info| info.as_ref())
+            .map(IoApic::new)
+            .find(|ioapic| ioapic.handles(route.gsi));
+
+        if let Some(ioapic) = ioapic {
+            let mut entry = (IRQ_BASE + irq) as u64 | (lapic.id() as u64) << 56;
+            if route.active_low {
+                entry |= REDIRECTION_ACTIVE_LOW;
+            }
+            if route.level {
+                entry |= REDIRECTION_LEVEL;
+            }
+            if pic_mask & (1 << irq) != 0 {
+                entry |= REDIRECTION_MASKED;
+            }
+            let index = route.gsi - ioapic.gsi_base;
+            ioapic.set_redirection(index, entry);
+            routes[irq as usize] = Some(Route { ioapic_base: ioapic.base, index: index, entry: entry });
+        }
+    }
+
+    // from here on handlers must acknowledge through the local APIC
+    LAPIC_BASE.store(lapic.base, Ordering::SeqCst);
+    ENABLED.store(true, Ordering::SeqCst);
+    drop(routes);
+
+    // IRQ0 stays masked on the I/O APIC, the local APIC timer raises its vector instead
+    let frequency = match pit::frequency() {
+        0 => pit::DEFAULT_FREQUENCY,
+        frequency => frequency,
+    };
+    lapic.start_timer(IRQ_BASE, frequency);
+}
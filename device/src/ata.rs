@@ -48,6 +48,12 @@ impl Ata {
     }
 }
 
+// IRQ14 handler. Transfers are polled, reading the regular status port only
+// acknowledges the drive's interrupt.
+pub fn handle_interrupt() {
+    unsafe { inb(AtaBus::COMMAND.bits) };
+}
+
 impl Disk for Ata{
     // Send 0xE0 for the "master" or 0xF0 for the "slave", ORed with the highest 4 bits of the LBA to port 0x1F6: outb(0x1F6, 0xE0 | (slavebit << 4) | ((LBA >> 24) & 0x0F))
     // Send a NULL byte to port 0x1F1, if you like (it is ignored and wastes lots of CPU time): outb(0x1F1, 0x00)
@@ -23,3 +23,17 @@ pub unsafe fn inw(port: u16) -> u16 {
     asm!("inw %dx, %ax" : "={ax}"(ret) : "{dx}"(port) :: "volatile");
     ret
 }
+
+// Read the model specific register `msr`
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) : "memory" : "volatile");
+    ((high as u64) << 32) | (low as u64)
+}
+
+// Write `value` to the model specific register `msr`
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
+}
@@ -2,7 +2,8 @@
 // http://www.computer-engineering.org/ps2keyboard/scancodes1.html
 
 use io::inb;
-use spin::Mutex;
+use sync::{self, IrqMutex};
+use tty;
 
 // Scancodes range 0x01 ... 0x0E
 const ASCII_PART_1: &'static [u8; 14] = b"\x1B1234567890-=\x08";
@@ -52,6 +53,15 @@ impl Keyboard {
 
     fn read_char(&mut self) -> Option<char>{
         self.read_scancode();
+        self.translate()
+    }
+
+    fn process(&mut self, scancode: Scancode) -> Option<char> {
+        self.scancode = scancode;
+        self.translate()
+    }
+
+    fn translate(&mut self) -> Option<char> {
         self.update();
         self.scancode.to_ascii().map(|ascii| {
             self.state.modify(ascii) as char
@@ -118,7 +128,7 @@ impl Modifiers {
     }
 }
 
-static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+static KEYBOARD: IrqMutex<Keyboard> = IrqMutex::new(Keyboard {
     scancode: Scancode(0x00),
     state: Modifiers::new()
 });
@@ -126,3 +136,36 @@ static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
 pub fn read_char() -> Option<char> {
     KEYBOARD.lock().read_char()
 }
+
+// Modifiers of polled input. Kept apart from `KEYBOARD`, whose holder the
+// debugger may have interrupted.
+static mut POLLED: Keyboard = Keyboard {
+    scancode: Scancode(0x00),
+    state: Modifiers::new()
+};
+
+// Polled input for when interrupts are off, e.g. in the debugger. Takes no
+// lock, so it must not run on two CPUs at once.
+pub fn poll_char() -> Option<char> {
+    // bit 0 of the status register: output buffer full
+    if unsafe { inb(0x64) } & 0x01 == 0 {
+        return None;
+    }
+    unsafe { POLLED.read_char() }
+}
+
+// IRQ1 handler. Only fetches the scancode, which has to be read before the
+// controller sends the next one, and defers the rest.
+pub fn handle_interrupt() {
+    let scancode = unsafe { inb(0x60) };
+    // nothing to do about a full queue but drop the key
+    let _ = sync::defer(handle_scancode, scancode as usize);
+}
+
+// Bottom half of IRQ1, feeds the pressed key to the TTY.
+fn handle_scancode(scancode: usize) {
+    let c = KEYBOARD.lock().process(Scancode(scancode as u8));
+    if let Some(c) = c {
+        tty::TTY_BUF.lock().input(c);
+    }
+}
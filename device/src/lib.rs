@@ -8,6 +8,11 @@ pub mod keyboard;
 pub mod tty;
 pub mod disk;
 pub mod ata;
+pub mod serial;
+pub mod pit;
+pub mod acpi;
+pub mod apic;
+pub mod rtc;
 
 #[macro_use]
 extern crate vga;
@@ -15,3 +20,5 @@ extern crate vga;
 #[macro_use]
 extern crate bitflags;
 extern crate spin;
+extern crate sync;
+extern crate memory;
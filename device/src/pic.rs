@@ -65,10 +65,102 @@ pub fn remap() {
 // End-of-interrupt command code
 const PIC_EOI: u8 = 0x20;
 
-pub fn send_eoi(interrupt_number: isize) {
-    // TODO
-    if interrupt_number >= 8 {
-        unsafe { outb(PIC2_COMMAND,PIC_EOI); }
+// OCW3 commands selecting which register the next command port read returns
+const PIC_READ_IRR: u8 = 0x0a;          // Interrupt Request Register: raised, not yet serviced
+const PIC_READ_ISR: u8 = 0x0b;          // In-Service Register: being serviced, not yet EOI'd
+
+// The slave is cascaded on this line of the master
+const CASCADE_IRQ: u8 = 2;
+
+// IRQ numbers are 0..7 on the master and 8..15 on the slave.
+// Sending EOI for a slave IRQ has to acknowledge the master's cascade line too.
+pub fn send_eoi(irq: u8) {
+    assert!(irq < 16, "invalid IRQ {}", irq);
+    if irq >= 8 {
+        unsafe { outb(PIC2_COMMAND, PIC_EOI); }
+    }
+    unsafe { outb(PIC1_COMMAND, PIC_EOI); }
+}
+
+// Returns the interrupt masks of both PICs, master in the low byte.
+pub fn get_mask() -> u16 {
+    unsafe { (inb(PIC2_DATA) as u16) << 8 | inb(PIC1_DATA) as u16 }
+}
+
+// Mask every line on both PICs, e.g. before switching to the APIC.
+pub fn disable() {
+    unsafe {
+        outb(PIC1_DATA, 0xFF);
+        outb(PIC2_DATA, 0xFF);
+    }
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    assert!(irq < 16, "invalid IRQ {}", irq);
+    let (port, line) = if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    };
+    unsafe {
+        let mask = inb(port);
+        if masked {
+            outb(port, mask | (1 << line));
+        } else {
+            outb(port, mask & !(1 << line));
+        }
+    }
+}
+
+pub fn mask_irq(irq: u8) {
+    set_masked(irq, true);
+}
+
+// Unmasking a slave line also unmasks the cascade on the master,
+// otherwise the interrupt would never get through.
+pub fn unmask_irq(irq: u8) {
+    set_masked(irq, false);
+    if irq >= 8 {
+        set_masked(CASCADE_IRQ, false);
+    }
+}
+
+fn get_irq_reg(ocw3: u8) -> u16 {
+    unsafe {
+        outb(PIC1_COMMAND, ocw3);
+        outb(PIC2_COMMAND, ocw3);
+        (inb(PIC2_COMMAND) as u16) << 8 | inb(PIC1_COMMAND) as u16
+    }
+}
+
+// Interrupt Request Register of both PICs, master in the low byte.
+pub fn get_irr() -> u16 {
+    get_irq_reg(PIC_READ_IRR)
+}
+
+// In-Service Register of both PICs, master in the low byte.
+pub fn get_isr() -> u16 {
+    get_irq_reg(PIC_READ_ISR)
+}
+
+// A PIC raises IRQ7 (master) or IRQ15 (slave) when the request that started
+// the interrupt went away before it was acknowledged. Such an IRQ has no bit
+// in the ISR and must not get an EOI, but for a spurious IRQ15 the master
+// did see a real IRQ2 from the slave and still needs its EOI.
+//
+// Returns true if the interrupt should be ignored.
+// see https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+pub fn is_spurious(irq: u8) -> bool {
+    match irq {
+        7 => get_isr() & (1 << 7) == 0,
+        15 => {
+            if get_isr() & (1 << 15) == 0 {
+                unsafe { outb(PIC1_COMMAND, PIC_EOI); }
+                true
+            } else {
+                false
+            }
+        }
+        _ => false,
     }
-    unsafe {outb(PIC1_COMMAND,PIC_EOI); }
 }
@@ -0,0 +1,114 @@
+// 8253/8254 Programmable Interval Timer
+// follow https://wiki.osdev.org/Programmable_Interval_Timer
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use sync::IrqMutex;
+use io::{inb, outb};
+
+const CHANNEL0: u16     = 0x40;    // Channel 0 data port, wired to IRQ0
+const CHANNEL2: u16     = 0x42;    // Channel 2 data port, wired to the PC speaker
+const COMMAND: u16      = 0x43;    // Mode/Command register (write only)
+const SPEAKER_PORT: u16 = 0x61;    // bit 0 gates channel 2, bit 5 reflects its output
+
+// The oscillator used by the PIT chip runs at (roughly) 1.193182 MHz.
+const BASE_FREQUENCY: u32 = 1193182;
+
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+const MAX_CALLBACKS: usize = 8;
+
+static FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+static CALLBACKS: IrqMutex<[Option<fn(usize)>; MAX_CALLBACKS]> = IrqMutex::new([None; MAX_CALLBACKS]);
+
+// Program channel 0 to fire IRQ0 `frequency` times per second.
+// The divisor is 16 bits wide, so the slowest rate is about 18.2 Hz. Square
+// wave mode needs a divisor of at least 2, so the fastest is about 596 kHz.
+pub fn init(frequency: u32) {
+    assert!(frequency > 18 && frequency <= BASE_FREQUENCY / 2,
+            "unsupported PIT frequency {}", frequency);
+    let divisor = BASE_FREQUENCY / frequency;
+    unsafe {
+        outb(COMMAND, 0x36);                    // channel 0, lobyte/hibyte, mode 3 (square wave)
+        outb(CHANNEL0, divisor as u8);
+        outb(CHANNEL0, (divisor >> 8) as u8);
+    }
+    FREQUENCY.store((BASE_FREQUENCY / divisor) as usize, Ordering::SeqCst);
+}
+
+// The actual interrupt rate, which may differ slightly from the requested one.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::SeqCst) as u32
+}
+
+// Number of timer interrupts since `init`.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::SeqCst)
+}
+
+pub fn uptime_ms() -> usize {
+    let frequency = FREQUENCY.load(Ordering::SeqCst);
+    if frequency == 0 {
+        return 0;
+    }
+    ticks() * 1000 / frequency
+}
+
+// Must be called from the IRQ0 handler on every timer interrupt.
+pub fn tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    // copy the table out, so callbacks may (un)register themselves
+    let callbacks = *CALLBACKS.lock();
+    for callback in callbacks.iter() {
+        if let Some(callback) = *callback {
+            callback(ticks);
+        }
+    }
+}
+
+// Run `callback` with the current tick count on every timer interrupt.
+pub fn register_callback(callback: fn(usize)) -> Result<(), &'static str> {
+    let mut callbacks = CALLBACKS.lock();
+    match callbacks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(callback);
+            Ok(())
+        }
+        None => Err("Too many timer callbacks."),
+    }
+}
+
+pub fn unregister_callback(callback: fn(usize)) {
+    let mut callbacks = CALLBACKS.lock();
+    for slot in callbacks.iter_mut() {
+        if *slot == Some(callback) {
+            *slot = None;
+        }
+    }
+}
+
+// Busy-wait for `ms` milliseconds.
+// Uses channel 2 in one-shot mode, so it works before interrupts are enabled
+// and does not touch the channel 0 tick rate.
+pub fn sleep_ms(ms: u32) {
+    for _ in 0..ms {
+        unsafe { wait_channel2((BASE_FREQUENCY / 1000) as u16) };
+    }
+}
+
+unsafe fn wait_channel2(count: u16) {
+    // disconnect the speaker and raise the gate of channel 2
+    let speaker = inb(SPEAKER_PORT) & !0x02;
+    outb(SPEAKER_PORT, speaker | 0x01);
+
+    outb(COMMAND, 0xB0);                        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    outb(CHANNEL2, count as u8);
+    outb(CHANNEL2, (count >> 8) as u8);
+
+    // restart the count by toggling the gate
+    outb(SPEAKER_PORT, speaker & !0x01);
+    outb(SPEAKER_PORT, speaker | 0x01);
+
+    // the output goes high once the counter reaches zero
+    while inb(SPEAKER_PORT) & 0x20 == 0 {}
+}
@@ -0,0 +1,200 @@
+// CMOS real-time clock
+// follow https://wiki.osdev.org/CMOS and https://wiki.osdev.org/RTC
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use io::{inb, outb};
+use sync::IrqMutex;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16    = 0x71;
+
+// CMOS registers
+const REG_SECONDS: u8  = 0x00;
+const REG_MINUTES: u8  = 0x02;
+const REG_HOURS: u8    = 0x04;
+const REG_DAY: u8      = 0x07;
+const REG_MONTH: u8    = 0x08;
+const REG_YEAR: u8     = 0x09;
+const REG_STATUS_A: u8 = 0x0A;    // bit 7: update in progress, bits 0..3: periodic rate
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;    // interrupt flags, reading it acknowledges IRQ8
+const REG_STATUS_D: u8 = 0x0D;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8   = 1 << 1;
+const STATUS_B_BINARY: u8    = 1 << 2;
+const STATUS_B_PERIODIC: u8  = 1 << 6;
+const STATUS_C_PERIODIC: u8  = 1 << 6;
+const HOUR_PM: u8            = 1 << 7;
+const NMI_DISABLE: u8        = 1 << 7;    // in the index written to port 0x70
+
+// The RTC only stores two digits of the year. The century register is not at
+// a fixed location (see the FADT), so assume we are in the 21st century.
+const CENTURY: u16 = 2000;
+
+static PERIODIC_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+// Guards the index/data port pair, which the IRQ8 handler uses as well. The
+// value is the NMI disable bit, which every index written keeps.
+static CMOS: IrqMutex<u8> = IrqMutex::new(0);
+
+// Field order makes the derived ordering chronological.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year:   u16,
+    pub month:  u8,    // 1..12
+    pub day:    u8,    // 1..31
+    pub hour:   u8,    // 0..23
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // Seconds since 1970-01-01 00:00:00 UTC, assuming the RTC runs on UTC.
+    pub fn timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// Days between 1970-01-01 and the given date of the proleptic Gregorian calendar.
+// see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn read_register(reg: u8) -> u8 {
+    let nmi = CMOS.lock();
+    unsafe {
+        outb(CMOS_ADDRESS, *nmi | reg);
+        inb(CMOS_DATA)
+    }
+}
+
+fn write_register(reg: u8, value: u8) {
+    let nmi = CMOS.lock();
+    unsafe {
+        outb(CMOS_ADDRESS, *nmi | reg);
+        outb(CMOS_DATA, value);
+    }
+}
+
+// Mask or unmask NMIs with bit 7 of the CMOS index port.
+pub fn set_nmi_enabled(enabled: bool) {
+    let mut nmi = CMOS.lock();
+    *nmi = if enabled { 0 } else { NMI_DISABLE };
+    unsafe { outb(CMOS_ADDRESS, *nmi | REG_STATUS_D) };
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+// Raw register values in the order second, minute, hour, day, month, year.
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {}
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+// Read the current date and time.
+pub fn read() -> DateTime {
+    // An update may still start right after the UIP check, so read until
+    // two consecutive reads agree.
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+
+    // in 12 hour mode the top bit of the hour marks PM
+    let pm = raw[2] & HOUR_PM != 0;
+    let mut hour = convert(raw[2] & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour = match (hour, pm) {
+            (12, false) => 0,
+            (12, true)  => 12,
+            (_, true)   => hour + 12,
+            (_, false)  => hour,
+        };
+    }
+
+    DateTime {
+        year:   CENTURY + convert(raw[5]) as u16,
+        month:  convert(raw[4]),
+        day:    convert(raw[3]),
+        hour:   hour,
+        minute: convert(raw[1]),
+        second: convert(raw[0]),
+    }
+}
+
+// Seconds since the Unix epoch.
+pub fn timestamp() -> u64 {
+    read().timestamp()
+}
+
+// Enable the periodic interrupt on IRQ8 at 32768 >> (rate - 1) Hz.
+// `rate` must be within 3..15, i.e. 8 kHz down to 2 Hz.
+pub fn enable_periodic(rate: u8) {
+    assert!(rate >= 3 && rate <= 15, "invalid RTC rate {}", rate);
+    let status_a = read_register(REG_STATUS_A);
+    write_register(REG_STATUS_A, (status_a & 0xF0) | rate);
+    let status_b = read_register(REG_STATUS_B);
+    write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+    // discard anything pending, otherwise IRQ8 never fires again
+    read_register(REG_STATUS_C);
+}
+
+pub fn disable_periodic() {
+    let status_b = read_register(REG_STATUS_B);
+    write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+}
+
+// Number of periodic interrupts since `enable_periodic`.
+pub fn periodic_ticks() -> usize {
+    PERIODIC_TICKS.load(Ordering::SeqCst)
+}
+
+// Must be called from the IRQ8 handler.
+pub fn handle_interrupt() {
+    // the RTC raises no further interrupts until status C has been read
+    let flags = read_register(REG_STATUS_C);
+    if flags & STATUS_C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+}
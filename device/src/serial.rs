@@ -0,0 +1,154 @@
+// 16550 UART serial port
+// follow https://wiki.osdev.org/Serial_Ports
+
+use core::fmt::{self, Write};
+use sync::{self, IrqMutex};
+use io::{inb, outb};
+use tty;
+
+const COM1_BASE: u16 = 0x3F8;    // IRQ4
+const COM2_BASE: u16 = 0x2F8;    // IRQ3
+
+// Register offsets relative to the port base.
+// When DLAB (bit 7 of the line control register) is set, offset 0 and 1
+// hold the low and high byte of the baud rate divisor instead.
+const DATA: u16          = 0;    // Receive buffer / transmit holding register
+const INT_ENABLE: u16    = 1;    // Interrupt enable register
+const FIFO_CONTROL: u16  = 2;    // FIFO control register
+const LINE_CONTROL: u16  = 3;    // Line control register
+const MODEM_CONTROL: u16 = 4;    // Modem control register
+const LINE_STATUS: u16   = 5;    // Line status register
+
+// The UART is driven by a 1.8432 MHz crystal divided by 16.
+const UART_CLOCK: u32 = 115200;
+
+pub const DEFAULT_BAUD: u32 = 38400;
+
+bitflags! {
+    struct LineStatus: u8 {
+        const DATA_READY    = 1 << 0;
+        const OVERRUN_ERROR = 1 << 1;
+        const PARITY_ERROR  = 1 << 2;
+        const FRAMING_ERROR = 1 << 3;
+        const BREAK         = 1 << 4;
+        const THR_EMPTY     = 1 << 5;    // transmitter holding register empty
+        const TX_EMPTY      = 1 << 6;    // transmitter completely idle
+        const FIFO_ERROR    = 1 << 7;
+    }
+}
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+
+    const fn new(base: u16) -> Self {
+        SerialPort { base: base }
+    }
+
+    // Program the divisor for `baud`, use 8N1 framing, enable and clear
+    // the FIFOs and raise an interrupt whenever received data is available.
+    pub fn init(&mut self, baud: u32) {
+        assert!(baud > 0 && UART_CLOCK % baud == 0, "unsupported baud rate {}", baud);
+        let divisor = (UART_CLOCK / baud) as u16;
+        unsafe {
+            outb(self.base + INT_ENABLE, 0x00);              // disable all interrupts
+            outb(self.base + LINE_CONTROL, 0x80);            // enable DLAB
+            outb(self.base + DATA, divisor as u8);           // divisor low byte
+            outb(self.base + INT_ENABLE, (divisor >> 8) as u8); // divisor high byte
+            outb(self.base + LINE_CONTROL, 0x03);            // 8 bits, no parity, one stop bit
+            outb(self.base + FIFO_CONTROL, 0xC7);            // enable FIFO, clear them, 14-byte threshold
+            outb(self.base + MODEM_CONTROL, 0x0B);           // DTR, RTS and OUT2 (routes the IRQ)
+            outb(self.base + INT_ENABLE, 0x01);              // received data available interrupt
+        }
+    }
+
+    fn line_status(&self) -> LineStatus {
+        LineStatus::from_bits_truncate(unsafe { inb(self.base + LINE_STATUS) })
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        while !self.line_status().contains(LineStatus::THR_EMPTY) {}
+        unsafe { outb(self.base + DATA, byte) };
+    }
+
+    // Returns the next received byte, if any is waiting in the FIFO.
+    pub fn receive(&mut self) -> Option<u8> {
+        if self.line_status().contains(LineStatus::DATA_READY) {
+            Some(unsafe { inb(self.base + DATA) })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // terminals expect CRLF line endings
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+pub static COM1: IrqMutex<SerialPort> = IrqMutex::new(SerialPort::new(COM1_BASE));
+pub static COM2: IrqMutex<SerialPort> = IrqMutex::new(SerialPort::new(COM2_BASE));
+
+// COM1 and COM2 without taking their locks, for exception handlers that may
+// have interrupted the holder. A `SerialPort` is nothing but its base port,
+// so this is plain port I/O; output may interleave with the holder's.
+pub unsafe fn com1_unlocked() -> SerialPort {
+    SerialPort::new(COM1_BASE)
+}
+
+pub unsafe fn com2_unlocked() -> SerialPort {
+    SerialPort::new(COM2_BASE)
+}
+
+pub fn init(baud: u32) {
+    COM1.lock().init(baud);
+    COM2.lock().init(baud);
+}
+
+// Console sink for `kprint!`, see `vga::set_sink`.
+pub fn kprint(args: fmt::Arguments) {
+    COM1.lock().write_fmt(args).unwrap();
+}
+
+// Translate a received byte into the character the TTY expects.
+pub fn to_tty_char(byte: u8) -> char {
+    match byte {
+        b'\r' => '\n',
+        0x7F  => '\x08',    // terminals send DEL for backspace
+        _     => byte as char,
+    }
+}
+
+// Drain the port's FIFO, the bytes are passed to the TTY later.
+fn input(port: &IrqMutex<SerialPort>) {
+    let mut port = port.lock();
+    while let Some(byte) = port.receive() {
+        // nothing to do about a full queue but drop the byte
+        let _ = sync::defer(handle_byte, byte as usize);
+    }
+}
+
+// Bottom half of the serial interrupts, feeds a received byte to the TTY.
+fn handle_byte(byte: usize) {
+    tty::TTY_BUF.lock().input(to_tty_char(byte as u8));
+}
+
+// IRQ4 handler
+pub fn handle_com1_interrupt() {
+    input(&COM1);
+}
+
+// IRQ3 handler
+pub fn handle_com2_interrupt() {
+    input(&COM2);
+}
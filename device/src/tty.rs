@@ -5,7 +5,7 @@
 extern crate spin;
 extern crate vga;
 
-use spin::Mutex;
+use sync::IrqMutex;
 
 const NTTY_BUF: u32 = 512;
 
@@ -53,4 +53,4 @@ impl TTY_Buf {
     }
 }
 
-pub static TTY_BUF: Mutex<TTY_Buf> = Mutex::new(TTY_Buf::new());
+pub static TTY_BUF: IrqMutex<TTY_Buf> = IrqMutex::new(TTY_Buf::new());
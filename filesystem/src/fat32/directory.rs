@@ -1,6 +1,7 @@
 // follow https://wiki.osdev.org/FAT#Directories
 use super::super::File;
 use alloc::string::String;
+use device::rtc::DateTime;
 
 bitflags! {
     pub struct FileAttributes: u8 {
@@ -80,6 +81,53 @@ impl FatDirectory {
         let flag = FileAttributes::from_bits_truncate(self.attributes);
         flag.contains(FileAttributes::DIRECTORY)
     }
+
+    pub fn created(&self) -> DateTime {
+        let mut created = decode_timestamp(self.creation_date, self.creation_time);
+        // the tenths field holds 0..199, i.e. the odd second lost by the 2 second resolution
+        created.second += self.creation_time_precise / 100;
+        created
+    }
+
+    pub fn modified(&self) -> DateTime {
+        decode_timestamp(self.last_modified_date, self.last_modified_time)
+    }
+
+    // Only the date of the last access is recorded.
+    pub fn accessed(&self) -> DateTime {
+        decode_timestamp(self.last_accessed, 0)
+    }
+
+    // Stamp the entry as modified (and accessed) at `now`.
+    pub fn touch(&mut self, now: DateTime) {
+        let (date, time) = encode_timestamp(now);
+        self.last_modified_date = date;
+        self.last_modified_time = time;
+        self.last_accessed = date;
+    }
+}
+
+// FAT dates count years from 1980 (Year 7 bits | Month 4 bits | Day 5 bits),
+// times have a two second resolution (Hour 5 bits | Minutes 6 bits | Seconds/2 5 bits).
+fn decode_timestamp(date: u16, time: u16) -> DateTime {
+    DateTime {
+        year:   1980 + (date >> 9),
+        month:  ((date >> 5) & 0x0F) as u8,
+        day:    (date & 0x1F) as u8,
+        hour:   (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    }
+}
+
+fn encode_timestamp(datetime: DateTime) -> (u16, u16) {
+    let date = (datetime.year.saturating_sub(1980) & 0x7F) << 9
+        | (datetime.month as u16) << 5
+        | datetime.day as u16;
+    let time = (datetime.hour as u16) << 11
+        | (datetime.minute as u16) << 5
+        | (datetime.second as u16) / 2;
+    (date, time)
 }
 
 #[derive(Debug, Clone)]
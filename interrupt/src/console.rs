@@ -0,0 +1,47 @@
+// Console for exception context
+//
+// The interrupted code may hold the lock of the screen or of COM1, waiting
+// for it would hang the handler. So the screen is skipped while its lock is
+// taken and COM1 is written without its lock.
+
+use core::fmt::{self, Write};
+
+use device::serial;
+use vga;
+
+macro_rules! cprint {
+    ($($arg:tt)*) => ($crate::console::print(format_args!($($arg)*)));
+}
+
+macro_rules! cprintln {
+    ($fmt:expr) => (cprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (cprint!(concat!($fmt, "\n"), $($arg)*));
+}
+
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(mut writer) = vga::WRITER.try_lock() {
+            writer.write_str(s)?;
+        }
+        unsafe { serial::com1_unlocked() }.write_str(s)
+    }
+}
+
+pub fn print(args: fmt::Arguments) {
+    let _ = Console.write_fmt(args);
+}
+
+// Erase the character left of the cursor.
+pub fn backspace() {
+    if let Some(mut writer) = vga::WRITER.try_lock() {
+        writer.clear_left();
+    }
+    let _ = unsafe { serial::com1_unlocked() }.write_str("\x08 \x08");
+}
+
+// The next byte received on COM1, if any.
+pub fn receive() -> Option<u8> {
+    unsafe { serial::com1_unlocked() }.receive()
+}
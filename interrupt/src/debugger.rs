@@ -0,0 +1,173 @@
+// Interactive kernel debugger
+//
+// Exceptions and `int3` drop into a small command loop instead of halting the
+// system. Input is polled from the PS/2 keyboard and COM1, output goes to
+// the screen and COM1. No console lock is taken, the interrupted code may
+// hold one. Interrupts stay disabled while the debugger runs.
+
+use core::ptr;
+use core::str;
+
+use console;
+use device::{keyboard, serial};
+use exception;
+use frame::{InterruptStackFrame, SavedRegisters};
+use memory;
+
+const RFLAGS_TF: u64 = 1 << 8;    // trap flag, raises #DB after the next instruction
+
+const LINE_SIZE: usize = 64;
+const DEFAULT_DUMP: usize = 64;
+const MAX_DUMP: usize = 1024;
+
+const HELP: &'static str = "\
+help            show this message
+regs            show the registers and the interrupt frame
+x ADDR [LEN]    hexdump LEN bytes at ADDR (hex)
+t ADDR          translate ADDR through the page tables
+code            show the raw bytes around RIP
+s               single step
+c               continue
+halt            stop the system";
+
+// Stop in the debugger right here.
+pub fn breakpoint() {
+    unsafe { asm!("int3" :::: "volatile") };
+}
+
+fn read_char() -> char {
+    loop {
+        if let Some(c) = keyboard::poll_char() {
+            return c;
+        }
+        if let Some(byte) = console::receive() {
+            return serial::to_tty_char(byte);
+        }
+    }
+}
+
+fn read_line(buf: &mut [u8; LINE_SIZE]) -> &str {
+    let mut len = 0;
+    loop {
+        match read_char() {
+            '\n' => break,
+            '\x08' => if len > 0 {
+                len -= 1;
+                console::backspace();
+            },
+            c if c >= ' ' && c <= '~' && len < buf.len() => {
+                buf[len] = c as u8;
+                len += 1;
+                cprint!("{}", c);
+            },
+            _ => {},
+        }
+    }
+    cprintln!("");
+    str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+fn parse_address(arg: &str) -> Option<usize> {
+    let digits = if arg.starts_with("0x") { &arg[2..] } else { arg };
+    usize::from_str_radix(digits, 16).ok()
+}
+
+// Read a byte, unless its page is not mapped.
+fn read_byte(address: usize) -> Option<u8> {
+    memory::translate(address).map(|_| unsafe { ptr::read_volatile(address as *const u8) })
+}
+
+fn hexdump(start: usize, len: usize) {
+    let end = start.saturating_add(len);
+    let mut line = start & !0xF;
+    while line < end {
+        cprint!("{:016x}  ", line);
+        for address in line..line + 16 {
+            if address < start || address >= end {
+                cprint!("   ");
+            } else {
+                match read_byte(address) {
+                    Some(byte) => cprint!("{:02x} ", byte),
+                    None => cprint!("?? "),
+                }
+            }
+        }
+        cprint!(" ");
+        for address in line..line + 16 {
+            match read_byte(address) {
+                Some(byte) if address >= start && address < end && byte >= 0x20 && byte < 0x7F
+                    => cprint!("{}", byte as char),
+                _ => cprint!("."),
+            }
+        }
+        cprintln!("");
+        line += 16;
+    }
+}
+
+// Raw bytes from 16 before to 16 after RIP, the one at RIP in brackets.
+fn print_code(rip: usize) {
+    cprint!("{:016x}  ", rip);
+    for address in rip.saturating_sub(16)..rip.saturating_add(16) {
+        let byte = read_byte(address);
+        match (address == rip, byte) {
+            (true, Some(byte)) => cprint!("[{:02x}]", byte),
+            (true, None) => cprint!("[??]"),
+            (false, Some(byte)) => cprint!("{:02x}", byte),
+            (false, None) => cprint!("??"),
+        }
+    }
+    cprintln!("");
+}
+
+fn translate(address: usize) {
+    match memory::translate(address) {
+        Some(physical) => cprintln!("{:#018x} -> {:#018x}", address, physical),
+        None => cprintln!("{:#018x} is not mapped", address),
+    }
+}
+
+// Run the command loop for exception `vector`. Returns true if execution
+// should resume, possibly for a single step, false to halt.
+pub fn enter(vector: u8, stack: &mut InterruptStackFrame, regs: &mut SavedRegisters,
+             error_code: Option<u64>) -> bool {
+    // a single step ends here
+    stack.rflags &= !RFLAGS_TF;
+
+    cprintln!("kdb: {} at {:#x}, type `help` for commands", exception::name(vector), stack.rip);
+    print_code(stack.rip as usize);
+
+    let mut buf = [0u8; LINE_SIZE];
+    loop {
+        cprint!("kdb> ");
+        let line = read_line(&mut buf);
+        let mut args = line.split_whitespace();
+        match args.next() {
+            None => {},
+            Some("help") => cprintln!("{}", HELP),
+            Some("regs") => exception::dump(vector, stack, regs, error_code),
+            Some("x") => match args.next().and_then(parse_address) {
+                Some(address) => {
+                    let len = args.next()
+                        .and_then(parse_address)
+                        .unwrap_or(DEFAULT_DUMP)
+                        .min(MAX_DUMP);
+                    hexdump(address, len);
+                },
+                None => cprintln!("usage: x ADDR [LEN]"),
+            },
+            Some("t") => match args.next().and_then(parse_address) {
+                Some(address) => translate(address),
+                None => cprintln!("usage: t ADDR"),
+            },
+            Some("code") => print_code(stack.rip as usize),
+            Some("s") => {
+                stack.rflags |= RFLAGS_TF;
+                return true;
+            },
+            Some("c") => return true,
+            Some("halt") => return false,
+            Some(command) => cprintln!("unknown command `{}`", command),
+        }
+    }
+}
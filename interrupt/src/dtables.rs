@@ -1,31 +1,53 @@
 use core::mem::size_of;
 
-use idt::IdtEntry;
-
 #[repr(C, packed)]
 pub struct DescriptorTablePointer {
-    // Size of the DT.
+    // Size of the DT minus one.
     pub limit: u16,
-    // Pointer to the memory region containing the IDT.
-    pub base: *const IdtEntry,
+    // Pointer to the memory region containing the DT.
+    pub base: usize,
 }
 
 impl DescriptorTablePointer {
-    fn new(slice: &[IdtEntry]) -> Self {
-        let len = slice.len() * size_of::<IdtEntry>();
-        assert!(len < 0x10000);
+    fn new<T>(slice: &[T]) -> Self {
+        let len = slice.len() * size_of::<T>();
+        assert!(len <= 0x10000);
         DescriptorTablePointer {
-            base:  slice.as_ptr(),
-            limit: len as u16,
+            base:  slice.as_ptr() as usize,
+            limit: (len - 1) as u16,
         }
     }
 
-    pub fn new_idtp(idt: &[IdtEntry]) -> Self {
+    pub fn new_idtp<T>(idt: &[T]) -> Self {
         Self::new(idt)
     }
+
+    pub fn new_gdtp(gdt: &[u64]) -> Self {
+        Self::new(gdt)
+    }
 }
 
 // Load IDT table.
 pub unsafe fn lidt(idt: &DescriptorTablePointer) {
     asm!("lidt ($0)" :: "r" (idt) : "memory");
 }
+
+// Load GDT table.
+pub unsafe fn lgdt(gdt: &DescriptorTablePointer) {
+    asm!("lgdt ($0)" :: "r" (gdt) : "memory");
+}
+
+// Load the task register with the selector of a TSS descriptor.
+pub unsafe fn ltr(selector: u16) {
+    asm!("ltr $0" :: "r" (selector) : "memory");
+}
+
+// Reload the code segment register, which can't be written with `mov`,
+// by returning far to the next instruction.
+pub unsafe fn set_cs(selector: u16) {
+    asm!("pushq $0
+          leaq 1f(%rip), %rax
+          pushq %rax
+          lretq
+          1:" :: "ri" (selector as u64) : "rax" "memory");
+}
@@ -0,0 +1,208 @@
+// CPU exceptions, vectors 0..31
+// see https://wiki.osdev.org/Exceptions
+
+use core::intrinsics;
+
+use frame::{InterruptStackFrame, SavedRegisters};
+use debugger;
+use fpu;
+use gdbstub;
+use memory;
+use stats;
+use super::IDT;
+
+pub const DEBUG: u8                = 1;
+pub const BREAKPOINT: u8           = 3;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8         = 8;
+pub const PAGE_FAULT: u8           = 14;
+
+const EXCEPTION_NAMES: [&'static str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+bitflags! {
+    pub struct PageFaultErrorCode: u64 {
+        const PROTECTION_VIOLATION = 1 << 0;    // clear: the page was not present
+        const CAUSED_BY_WRITE      = 1 << 1;
+        const USER_MODE            = 1 << 2;
+        const MALFORMED_TABLE      = 1 << 3;    // a reserved bit was set in a page table
+        const INSTRUCTION_FETCH    = 1 << 4;
+    }
+}
+
+pub fn name(vector: u8) -> &'static str {
+    EXCEPTION_NAMES.get(vector as usize).cloned().unwrap_or("Unknown")
+}
+
+// The faulting address of the last page fault.
+pub fn read_cr2() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr2, $0" : "=r"(value)) };
+    value
+}
+
+pub fn dump(vector: u8, stack: &InterruptStackFrame, regs: &SavedRegisters,
+            error_code: Option<u64>) {
+    cprintln!("\nEXCEPTION: {} (vector {})", name(vector), vector);
+    cprintln!("RIP    {:#018x}  CS {:#06x}", stack.rip, stack.cs);
+    cprintln!("RSP    {:#018x}  SS {:#06x}", stack.rsp, stack.ss);
+    cprintln!("RFLAGS {:#018x}", stack.rflags);
+    dump_registers(regs);
+    if let Some(error_code) = error_code {
+        cprintln!("ERROR  {:#018x}", error_code);
+    }
+    if vector == PAGE_FAULT {
+        cprintln!("CR2    {:#018x}  {:?}", read_cr2(),
+                  PageFaultErrorCode::from_bits_truncate(error_code.unwrap_or(0)));
+    }
+}
+
+pub fn dump_registers(regs: &SavedRegisters) {
+    cprintln!("RAX {:#018x} RBX {:#018x} RCX {:#018x}", regs.rax, regs.rbx, regs.rcx);
+    cprintln!("RDX {:#018x} RSI {:#018x} RDI {:#018x}", regs.rdx, regs.rsi, regs.rdi);
+    cprintln!("RBP {:#018x} R8  {:#018x} R9  {:#018x}", regs.rbp, regs.r8, regs.r9);
+    cprintln!("R10 {:#018x} R11 {:#018x} R12 {:#018x}", regs.r10, regs.r11, regs.r12);
+    cprintln!("R13 {:#018x} R14 {:#018x} R15 {:#018x}", regs.r13, regs.r14, regs.r15);
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile") };
+    }
+}
+
+// Common entry of every exception stub. Anything not handled here ends up in
+// GDB, if attached, or the built-in debugger, which decide whether execution
+// resumes.
+pub fn handle(vector: u8, stack: &mut InterruptStackFrame, regs: &mut SavedRegisters,
+              error_code: Option<u64>) {
+    stats::record(vector);
+    if vector == DEVICE_NOT_AVAILABLE && fpu::handle_device_not_available() {
+        return;
+    }
+    if vector == PAGE_FAULT && handle_page_fault(error_code.unwrap_or(0)) {
+        return;
+    }
+    if vector != DEBUG && vector != BREAKPOINT {
+        dump(vector, stack, regs, error_code);
+    }
+    if vector == DOUBLE_FAULT {
+        if let Some((bottom, top)) = memory::guarded_stack(read_cr2() as usize) {
+            cprintln!("Stack overflow, hit the guard page of the stack {:#x}..{:#x}", bottom, top);
+        }
+    }
+    // there is no sane state to return to after a double fault
+    if vector != DOUBLE_FAULT && resume(vector, stack, regs, error_code) {
+        return;
+    }
+    cprintln!("System halted.");
+    halt();
+}
+
+// Demand paging and copy on write, returns true if the faulting access can
+// be retried.
+fn handle_page_fault(error_code: u64) -> bool {
+    let address = read_cr2();
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    match memory::handle_page_fault(address as usize,
+                                    error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+                                    error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)) {
+        Ok(()) => true,
+        Err(fault) => {
+            cprintln!("\nPage fault at {:#x}: {}", address, fault);
+            false
+        }
+    }
+}
+
+fn resume(vector: u8, stack: &mut InterruptStackFrame, regs: &mut SavedRegisters,
+          error_code: Option<u64>) -> bool {
+    if gdbstub::is_enabled() {
+        gdbstub::enter(vector, stack, regs)
+    } else {
+        debugger::enter(vector, stack, regs, error_code)
+    }
+}
+
+pub fn init() {
+    exception!(exception0, 0);
+    exception!(exception1, 1);
+    exception!(exception2, 2);
+    exception!(exception3, 3);
+    exception!(exception4, 4);
+    exception!(exception5, 5);
+    exception!(exception6, 6);
+    exception!(exception7, 7);
+    exception_error!(exception8, 8);
+    exception!(exception9, 9);
+    exception_error!(exception10, 10);
+    exception_error!(exception11, 11);
+    exception_error!(exception12, 12);
+    exception_error!(exception13, 13);
+    exception_error!(exception14, 14);
+    exception!(exception15, 15);
+    exception!(exception16, 16);
+    exception_error!(exception17, 17);
+    exception!(exception18, 18);
+    exception!(exception19, 19);
+    exception!(exception20, 20);
+    exception_error!(exception21, 21);
+    exception!(exception22, 22);
+    exception!(exception23, 23);
+    exception!(exception24, 24);
+    exception!(exception25, 25);
+    exception!(exception26, 26);
+    exception!(exception27, 27);
+    exception!(exception28, 28);
+    exception_error!(exception29, 29);
+    exception_error!(exception30, 30);
+    exception!(exception31, 31);
+
+    let stubs: [unsafe extern fn(); 32] = [
+        exception0,  exception1,  exception2,  exception3,
+        exception4,  exception5,  exception6,  exception7,
+        exception8,  exception9,  exception10, exception11,
+        exception12, exception13, exception14, exception15,
+        exception16, exception17, exception18, exception19,
+        exception20, exception21, exception22, exception23,
+        exception24, exception25, exception26, exception27,
+        exception28, exception29, exception30, exception31,
+    ];
+
+    let mut idt = IDT.lock();
+    for (vector, stub) in stubs.iter().enumerate() {
+        idt[vector].set_func(*stub);
+    }
+}
@@ -0,0 +1,121 @@
+// x87/SSE state handling
+//
+// The kernel itself is built without MMX and SSE (see x86_64-kurumi.json and
+// the check in lib.rs), so interrupt handlers never touch the FPU and the
+// stubs do not have to save it. Tasks which do use it are switched lazily:
+// the scheduler calls `switch_to` with the state of the next task, which sets
+// CR0.TS, and the first FPU instruction of that task raises #NM (vector 7).
+// Only then is the previous owner's state saved and the new one loaded.
+//
+// see https://wiki.osdev.org/SSE and Intel SDM Vol. 1 10.5 (FXSAVE area)
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const CR0_MP: u64 = 1 << 1;            // monitor coprocessor, WAIT honors TS
+const CR0_EM: u64 = 1 << 2;            // emulation, must be clear for SSE
+const CR0_TS: u64 = 1 << 3;            // task switched
+const CR4_OSFXSR: u64 = 1 << 9;        // FXSAVE/FXRSTOR and SSE available
+const CR4_OSXMMEXCPT: u64 = 1 << 10;   // unmasked SSE exceptions raise #XM
+
+// The 512 byte FXSAVE area, which has to be 16 byte aligned.
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    // The state after FNINIT with all SSE exceptions masked.
+    pub fn new() -> FpuState {
+        let mut area = [0u8; 512];
+        area[0] = 0x7F;                  // FCW = 0x037F
+        area[1] = 0x03;
+        area[24] = 0x80;                 // MXCSR = 0x1F80
+        area[25] = 0x1F;
+        FpuState(area)
+    }
+}
+
+// Address of the state currently loaded in the FPU, 0 if none.
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+// Address of the state of the running task, 0 if it has none.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn read_cr0() -> u64 {
+    let value: u64;
+    asm!("mov %cr0, $0" : "=r"(value));
+    value
+}
+
+unsafe fn write_cr0(value: u64) {
+    asm!("mov $0, %cr0" :: "r"(value) : "memory");
+}
+
+unsafe fn read_cr4() -> u64 {
+    let value: u64;
+    asm!("mov %cr4, $0" : "=r"(value));
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov $0, %cr4" :: "r"(value) : "memory");
+}
+
+unsafe fn clts() {
+    asm!("clts" :::: "volatile");
+}
+
+// FXSAVE/FXRSTOR are emitted as raw bytes, since the assembler refuses them
+// for a target without the fxsr feature.
+unsafe fn fxsave(state: *mut FpuState) {
+    asm!(".byte 0x0f, 0xae, 0x07" :: "{rdi}"(state) : "memory" : "volatile");    // fxsave (%rdi)
+}
+
+unsafe fn fxrstor(state: *const FpuState) {
+    asm!(".byte 0x0f, 0xae, 0x0f" :: "{rdi}"(state) : "memory" : "volatile");    // fxrstor (%rdi)
+}
+
+// Enable the FPU and SSE for code that opts into it.
+pub fn init() {
+    unsafe {
+        write_cr0((read_cr0() & !CR0_EM) | CR0_MP);
+        write_cr4(read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+        asm!("fninit" :::: "volatile");
+    }
+}
+
+// Make `state` the FPU state of the code that runs next. Pass a null pointer
+// for code without FPU state. Must be called with interrupts disabled and
+// `state` must stay valid until it is replaced or `release`d.
+pub fn switch_to(state: *mut FpuState) {
+    CURRENT.store(state as usize, Ordering::SeqCst);
+    unsafe {
+        if state as usize != 0 && OWNER.load(Ordering::SeqCst) == state as usize {
+            clts();    // the FPU still holds this state
+        } else {
+            write_cr0(read_cr0() | CR0_TS);
+        }
+    }
+}
+
+// Forget `state`, e.g. before the task owning it is freed.
+pub fn release(state: *mut FpuState) {
+    let _ = OWNER.compare_exchange(state as usize, 0, Ordering::SeqCst, Ordering::SeqCst);
+    let _ = CURRENT.compare_exchange(state as usize, 0, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+// #NM handler. Returns false if code without FPU state (e.g. the kernel
+// itself) used the FPU, which is a bug.
+pub fn handle_device_not_available() -> bool {
+    let current = CURRENT.load(Ordering::SeqCst);
+    if current == 0 {
+        return false;
+    }
+    unsafe {
+        clts();
+        let owner = OWNER.load(Ordering::SeqCst);
+        if owner != 0 {
+            fxsave(owner as *mut FpuState);
+        }
+        fxrstor(current as *const FpuState);
+    }
+    OWNER.store(current, Ordering::SeqCst);
+    true
+}
@@ -0,0 +1,35 @@
+// The CPU pushes this frame on every interrupt or exception,
+// see https://os.phil-opp.com/handling-exceptions/#the-interrupt-stack-frame
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub rip:    u64,    // the instruction to return to
+    pub cs:     u64,
+    pub rflags: u64,
+    pub rsp:    u64,    // stack pointer of the interrupted code
+    pub ss:     u64,
+}
+
+// The general purpose registers of the interrupted code, in the order the
+// stubs leave them on the stack (see `scratch_push!` and `preserved_push!`).
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct SavedRegisters {
+    // preserved registers
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    // scratch registers
+    pub r11: u64,
+    pub r10: u64,
+    pub r9:  u64,
+    pub r8:  u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+}
@@ -0,0 +1,480 @@
+// GDB remote serial protocol stub
+// see https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+//
+// Once enabled, exceptions are reported to a GDB attached to COM2 instead of
+// entering the built-in debugger, and GDB decides when execution resumes.
+// Only the basic packets are implemented: registers, memory, software
+// breakpoints, step and continue. Everything else gets the empty reply, which
+// tells GDB the packet is not supported. Unknown queries go to a hook, so
+// kernel state such as task lists can be exported later.
+//
+//     qemu-system-x86_64 ... -serial stdio -serial tcp::1234,server
+//     gdb build/kernel-x86_64.bin -ex 'target remote localhost:1234'
+
+use core::fmt;
+use core::ptr;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use sync::IrqMutex;
+
+use device::serial::{self, SerialPort};
+use exception::BREAKPOINT;
+use frame::{InterruptStackFrame, SavedRegisters};
+use memory;
+
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xCC;
+const RFLAGS_TF: u64 = 1 << 8;
+const CR0_WP: u64 = 1 << 16;
+
+// The x86_64 register file as GDB expects it in `g` and `G` packets: 17
+// 64 bit registers followed by eflags and the segment registers, 32 bit each.
+// GDB treats the x87 and SSE registers left out as unavailable.
+const GDB_REGISTERS_64: usize = 17;
+const GDB_REGISTERS_32: usize = 7;
+
+// Answers a `q` packet GDB sent, e.g. `qfThreadInfo`. Returns false if the
+// query is unknown.
+pub type QueryHandler = fn(query: &str, reply: &mut Packet) -> bool;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUERY_HANDLER: IrqMutex<Option<QueryHandler>> = IrqMutex::new(None);
+// Address and original byte of every breakpoint inserted
+static BREAKPOINTS: IrqMutex<[Option<(usize, u8)>; MAX_BREAKPOINTS]> =
+    IrqMutex::new([None; MAX_BREAKPOINTS]);
+
+// Report exceptions to GDB from now on. Follow with `debugger::breakpoint` to
+// wait for GDB to attach.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn set_query_handler(handler: Option<QueryHandler>) {
+    *QUERY_HANDLER.lock() = handler;
+}
+
+// An outgoing packet. The contents must not need escaping.
+pub struct Packet {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Packet {
+        Packet { buf: [0; PACKET_SIZE], len: 0 }
+    }
+
+    // Append raw bytes, anything beyond the packet size is dropped.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if self.len == PACKET_SIZE {
+                return;
+            }
+            self.buf[self.len] = *byte;
+            self.len += 1;
+        }
+    }
+
+    // Append `byte` as two hex digits.
+    pub fn push_hex(&mut self, byte: u8) {
+        self.push_bytes(&hex_digits(byte));
+    }
+
+    // Append `value` in target byte order, i.e. little endian.
+    fn push_le(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            self.push_hex((value >> (i * 8)) as u8);
+        }
+    }
+
+    fn send(&self) {
+        let checksum = self.buf[..self.len].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        loop {
+            {
+                let mut port = com2();
+                port.send(b'$');
+                for byte in &self.buf[..self.len] {
+                    port.send(*byte);
+                }
+                port.send(b'#');
+            }
+            write_bytes(&hex_digits(checksum));
+            // resend until GDB acknowledges
+            if read_byte() == b'+' {
+                return;
+            }
+        }
+    }
+}
+
+impl fmt::Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+// The port GDB is attached to. Its lock is not taken, the stub runs in
+// exception context and the interrupted code may hold it.
+fn com2() -> SerialPort {
+    unsafe { serial::com2_unlocked() }
+}
+
+fn read_byte() -> u8 {
+    loop {
+        if let Some(byte) = com2().receive() {
+            return byte;
+        }
+    }
+}
+
+fn write_bytes(bytes: &[u8]) {
+    let mut port = com2();
+    for byte in bytes {
+        port.send(*byte);
+    }
+}
+
+fn hex_digits(byte: u8) -> [u8; 2] {
+    const DIGITS: &'static [u8; 16] = b"0123456789abcdef";
+    [DIGITS[(byte >> 4) as usize], DIGITS[(byte & 0xF) as usize]]
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0' ... b'9' => Some(digit - b'0'),
+        b'a' ... b'f' => Some(digit - b'a' + 10),
+        b'A' ... b'F' => Some(digit - b'A' + 10),
+        _             => None,
+    }
+}
+
+fn parse_usize(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+// Parse `size` bytes of little endian hex from the front of `hex`.
+fn parse_le(hex: &[u8], size: usize) -> Option<u64> {
+    if hex.len() < size * 2 {
+        return None;
+    }
+    let mut value = 0;
+    for i in 0..size {
+        let high = hex_value(hex[i * 2])?;
+        let low = hex_value(hex[i * 2 + 1])?;
+        value |= (((high << 4) | low) as u64) << (i * 8);
+    }
+    Some(value)
+}
+
+// Wait for a packet with a valid checksum and acknowledge it. Returns the
+// length of its data in `buf`.
+fn receive_packet(buf: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        while read_byte() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum = 0u8;
+        let mut overflow = false;
+        loop {
+            let byte = read_byte();
+            if byte == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(byte);
+            if len < buf.len() {
+                buf[len] = byte;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+        }
+        let expected = (hex_value(read_byte()), hex_value(read_byte()));
+
+        match expected {
+            (Some(high), Some(low)) if !overflow && (high << 4) | low == checksum => {
+                write_bytes(b"+");
+                return len;
+            },
+            _ => write_bytes(b"-"),
+        }
+    }
+}
+
+// The POSIX signal GDB shows for an exception.
+fn signal(vector: u8) -> u8 {
+    match vector {
+        0 | 16 | 19 => 8,     // SIGFPE
+        1 | 3       => 5,     // SIGTRAP
+        6           => 4,     // SIGILL
+        5 | 13 | 14 => 11,    // SIGSEGV
+        17          => 7,     // SIGBUS
+        _           => 6,     // SIGABRT
+    }
+}
+
+fn read_cr0() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr0, $0" : "=r"(value)) };
+    value
+}
+
+unsafe fn write_cr0(value: u64) {
+    asm!("mov $0, %cr0" :: "r"(value) : "memory");
+}
+
+fn read_memory(address: usize) -> Option<u8> {
+    memory::translate(address).map(|_| unsafe { ptr::read_volatile(address as *const u8) })
+}
+
+// Write a byte, even to read-only pages such as the kernel's code.
+fn write_memory(address: usize, value: u8) -> Result<(), ()> {
+    if memory::translate(address).is_none() {
+        return Err(());
+    }
+    let cr0 = read_cr0();
+    unsafe {
+        write_cr0(cr0 & !CR0_WP);
+        ptr::write_volatile(address as *mut u8, value);
+        write_cr0(cr0);
+    }
+    Ok(())
+}
+
+fn insert_breakpoint(address: usize) -> Result<(), ()> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    if breakpoints.iter().any(|bp| bp.map_or(false, |(a, _)| a == address)) {
+        return Ok(());
+    }
+    let slot = breakpoints.iter_mut().find(|bp| bp.is_none()).ok_or(())?;
+    let original = read_memory(address).ok_or(())?;
+    write_memory(address, INT3)?;
+    *slot = Some((address, original));
+    Ok(())
+}
+
+fn remove_breakpoint(address: usize) -> Result<(), ()> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    for bp in breakpoints.iter_mut() {
+        if let Some((a, original)) = *bp {
+            if a == address {
+                write_memory(address, original)?;
+                *bp = None;
+                return Ok(());
+            }
+        }
+    }
+    Err(())
+}
+
+fn remove_all_breakpoints() {
+    let mut breakpoints = BREAKPOINTS.lock();
+    for bp in breakpoints.iter_mut() {
+        if let Some((address, original)) = bp.take() {
+            let _ = write_memory(address, original);
+        }
+    }
+}
+
+fn is_breakpoint(address: usize) -> bool {
+    BREAKPOINTS.lock().iter().any(|bp| bp.map_or(false, |(a, _)| a == address))
+}
+
+fn read_registers(stack: &InterruptStackFrame, regs: &SavedRegisters, reply: &mut Packet) {
+    let registers: [u64; GDB_REGISTERS_64] = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, stack.rsp,
+        regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        stack.rip,
+    ];
+    for value in registers.iter() {
+        reply.push_le(*value, 8);
+    }
+    // eflags, cs, ss, ds, es, fs, gs; the data segments are all null
+    let segments: [u64; GDB_REGISTERS_32] = [stack.rflags, stack.cs, stack.ss, 0, 0, 0, 0];
+    for value in segments.iter() {
+        reply.push_le(*value, 4);
+    }
+}
+
+// Segment registers are left alone, iretq would fault on a bad selector.
+fn write_registers(stack: &mut InterruptStackFrame, regs: &mut SavedRegisters,
+                   hex: &[u8]) -> Result<(), ()> {
+    let mut values = [0u64; GDB_REGISTERS_64];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = parse_le(&hex[(i * 16).min(hex.len())..], 8).ok_or(())?;
+    }
+    let rflags = parse_le(&hex[(GDB_REGISTERS_64 * 16).min(hex.len())..], 4).ok_or(())?;
+
+    regs.rax = values[0];
+    regs.rbx = values[1];
+    regs.rcx = values[2];
+    regs.rdx = values[3];
+    regs.rsi = values[4];
+    regs.rdi = values[5];
+    regs.rbp = values[6];
+    stack.rsp = values[7];
+    regs.r8 = values[8];
+    regs.r9 = values[9];
+    regs.r10 = values[10];
+    regs.r11 = values[11];
+    regs.r12 = values[12];
+    regs.r13 = values[13];
+    regs.r14 = values[14];
+    regs.r15 = values[15];
+    stack.rip = values[16];
+    stack.rflags = rflags;
+    Ok(())
+}
+
+// `m addr,length`
+fn read_memory_packet(args: &str, reply: &mut Packet) -> Result<(), ()> {
+    let mut args = args.split(',');
+    let address = args.next().and_then(parse_usize).ok_or(())?;
+    let length = args.next().and_then(parse_usize).ok_or(())?;
+    // GDB copes with a short read and asks for the rest
+    for i in 0..length.min(PACKET_SIZE / 2) {
+        match read_memory(address.wrapping_add(i)) {
+            Some(byte) => reply.push_hex(byte),
+            None if i == 0 => return Err(()),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+// `M addr,length:XX...`
+fn write_memory_packet(args: &str) -> Result<(), ()> {
+    let mut parts = args.splitn(2, ':');
+    let mut header = parts.next().ok_or(())?.split(',');
+    let data = parts.next().ok_or(())?.as_bytes();
+    let address = header.next().and_then(parse_usize).ok_or(())?;
+    let length = header.next().and_then(parse_usize).ok_or(())?;
+    if data.len() < length * 2 {
+        return Err(());
+    }
+    for i in 0..length {
+        let byte = parse_le(&data[i * 2..], 1).ok_or(())?;
+        write_memory(address.wrapping_add(i), byte as u8)?;
+    }
+    Ok(())
+}
+
+// `Z0,addr,kind` and `z0,addr,kind`, only software breakpoints are supported.
+fn breakpoint_packet(args: &str, insert: bool, reply: &mut Packet) {
+    let mut args = args.split(',');
+    if args.next() != Some("0") {
+        return;    // empty reply, not supported
+    }
+    let result = args.next().and_then(parse_usize).ok_or(()).and_then(|address| {
+        if insert { insert_breakpoint(address) } else { remove_breakpoint(address) }
+    });
+    reply.push_bytes(if result.is_ok() { b"OK" } else { b"E01" });
+}
+
+fn query_packet(query: &str, reply: &mut Packet) {
+    use core::fmt::Write;
+
+    if query.starts_with("Supported") {
+        write!(reply, "PacketSize={:x}", PACKET_SIZE).unwrap();
+    } else if query == "Attached" {
+        reply.push_bytes(b"1");    // detaching must not kill the kernel
+    } else {
+        let handler = *QUERY_HANDLER.lock();
+        if let Some(handler) = handler {
+            if !handler(query, reply) {
+                reply.len = 0;
+            }
+        }
+    }
+}
+
+// Resume at `addr` if the packet has one.
+fn resume_address(args: &str, stack: &mut InterruptStackFrame) {
+    if let Some(address) = parse_usize(args) {
+        stack.rip = address as u64;
+    }
+}
+
+// Report exception `vector` to GDB and serve its requests until it resumes
+// execution. Returns false if GDB killed the kernel.
+pub fn enter(vector: u8, stack: &mut InterruptStackFrame, regs: &mut SavedRegisters) -> bool {
+    use core::fmt::Write;
+
+    stack.rflags &= !RFLAGS_TF;
+    // int3 reports the address after itself, GDB wants the breakpoint's
+    if vector == BREAKPOINT && is_breakpoint((stack.rip as usize).wrapping_sub(1)) {
+        stack.rip -= 1;
+    }
+    let signal = signal(vector);
+
+    let mut stop = Packet::new();
+    write!(stop, "S{:02x}", signal).unwrap();
+    stop.send();
+
+    let mut buf = [0u8; PACKET_SIZE];
+    loop {
+        let len = receive_packet(&mut buf);
+        if len == 0 {
+            Packet::new().send();
+            continue;
+        }
+        // commands are a single byte, split before decoding the arguments
+        let command = buf[0];
+        let args = match str::from_utf8(&buf[1..len]) {
+            Ok(args) => args,
+            Err(_) => {
+                Packet::new().send();
+                continue;
+            },
+        };
+
+        let mut reply = Packet::new();
+        match command {
+            b'?' => write!(reply, "S{:02x}", signal).unwrap(),
+            b'g' => read_registers(stack, regs, &mut reply),
+            b'G' => match write_registers(stack, regs, args.as_bytes()) {
+                Ok(()) => reply.push_bytes(b"OK"),
+                Err(()) => reply.push_bytes(b"E01"),
+            },
+            b'm' => if read_memory_packet(args, &mut reply).is_err() {
+                reply.len = 0;
+                reply.push_bytes(b"E14");    // EFAULT
+            },
+            b'M' => match write_memory_packet(args) {
+                Ok(()) => reply.push_bytes(b"OK"),
+                Err(()) => reply.push_bytes(b"E14"),
+            },
+            b'Z' => breakpoint_packet(args, true, &mut reply),
+            b'z' => breakpoint_packet(args, false, &mut reply),
+            b'q' => query_packet(args, &mut reply),
+            b's' => {
+                resume_address(args, stack);
+                stack.rflags |= RFLAGS_TF;
+                return true;
+            },
+            b'c' => {
+                resume_address(args, stack);
+                return true;
+            },
+            b'D' => {
+                remove_all_breakpoints();
+                ENABLED.store(false, Ordering::SeqCst);
+                reply.push_bytes(b"OK");
+                reply.send();
+                return true;
+            },
+            b'k' => {
+                remove_all_breakpoints();
+                return false;
+            },
+            _ => {},
+        }
+        reply.send();
+    }
+}
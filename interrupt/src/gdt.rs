@@ -0,0 +1,161 @@
+// Global Descriptor Table and Task State Segment
+// see https://wiki.osdev.org/Global_Descriptor_Table and https://wiki.osdev.org/Task_State_Segment
+//
+// Replaces the GDT set up in boot.asm. In long mode segmentation is mostly
+// gone, but we still need a TSS to provide the Interrupt Stack Table, which
+// lets handlers like the double fault handler run on a known-good stack.
+
+use core::mem::size_of;
+use spin::Once;
+
+use dtables::{self, DescriptorTablePointer};
+use exception::DOUBLE_FAULT;
+use super::IDT;
+
+// Index into the Interrupt Stack Table used by the double fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+
+// 64 bit TSS, see Intel SDM Vol. 3A 7.7
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved_1:                u32,
+    pub privilege_stack_table: [u64; 3],    // stacks loaded on a privilege change to ring 0..2
+    reserved_2:                u64,
+    pub interrupt_stack_table: [u64; 7],    // IST1..IST7
+    reserved_3:                u64,
+    reserved_4:                u16,
+    pub iomap_base:            u16,
+}
+
+impl TaskStateSegment {
+    fn new() -> TaskStateSegment {
+        TaskStateSegment {
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            iomap_base: size_of::<TaskStateSegment>() as u16,    // no I/O permission map
+        }
+    }
+}
+
+bitflags! {
+    struct DescriptorFlags: u64 {
+        const WRITABLE     = 1 << 41;
+        const EXECUTABLE   = 1 << 43;
+        const USER_SEGMENT = 1 << 44;
+        const PRESENT      = 1 << 47;
+        const LONG_MODE    = 1 << 53;
+    }
+}
+
+enum Descriptor {
+    UserSegment(u64),
+    SystemSegment(u64, u64),    // takes two GDT slots
+}
+
+impl Descriptor {
+    fn kernel_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT |
+            DescriptorFlags::EXECUTABLE | DescriptorFlags::LONG_MODE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    fn kernel_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT |
+            DescriptorFlags::WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        let base = tss as *const _ as u64;
+        let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+
+        let mut low = DescriptorFlags::PRESENT.bits();
+        low |= limit & 0xFFFF;                     // limit 0..15
+        low |= (base & 0xFF_FFFF) << 16;           // base 0..23
+        low |= 0b1001 << 40;                       // type: available 64 bit TSS
+        low |= ((base >> 24) & 0xFF) << 56;        // base 24..31
+        let high = base >> 32;                     // base 32..63
+
+        Descriptor::SystemSegment(low, high)
+    }
+}
+
+struct Gdt {
+    table: [u64; 8],
+    next_free: usize,
+}
+
+impl Gdt {
+    fn new() -> Gdt {
+        // entry 0 is the mandatory null descriptor
+        Gdt { table: [0; 8], next_free: 1 }
+    }
+
+    // Returns the selector of the new entry.
+    fn add_entry(&mut self, entry: Descriptor) -> u16 {
+        let index = match entry {
+            Descriptor::UserSegment(value) => self.push(value),
+            Descriptor::SystemSegment(low, high) => {
+                let index = self.push(low);
+                self.push(high);
+                index
+            }
+        };
+        (index * 8) as u16    // RPL 0, GDT
+    }
+
+    fn push(&mut self, value: u64) -> usize {
+        assert!(self.next_free < self.table.len(), "GDT full");
+        let index = self.next_free;
+        self.table[index] = value;
+        self.next_free += 1;
+        index
+    }
+
+    fn load(&'static self) {
+        let ptr = DescriptorTablePointer::new_gdtp(&self.table[..self.next_free]);
+        unsafe { dtables::lgdt(&ptr) };
+    }
+}
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<Gdt> = Once::new();
+
+// Build and load the GDT and TSS. `interrupt_stacks` holds the stack tops for
+// IST1, IST2, ...; the double fault handler is switched to its stack.
+pub fn init(interrupt_stacks: &[usize]) {
+    assert!(interrupt_stacks.len() > DOUBLE_FAULT_IST_INDEX && interrupt_stacks.len() <= 7,
+            "need between 1 and 7 interrupt stacks");
+
+    let tss = TSS.call_once(|| {
+        let mut stacks = [0u64; 7];
+        for (index, top) in interrupt_stacks.iter().enumerate() {
+            stacks[index] = *top as u64;
+        }
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table = stacks;
+        tss
+    });
+
+    let mut code_selector = 0;
+    let mut tss_selector = 0;
+    let gdt = GDT.call_once(|| {
+        let mut gdt = Gdt::new();
+        code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        gdt.add_entry(Descriptor::kernel_data_segment());
+        tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        gdt
+    });
+    gdt.load();
+
+    unsafe {
+        dtables::set_cs(code_selector);
+        dtables::ltr(tss_selector);
+    }
+
+    IDT.lock()[DOUBLE_FAULT as usize].set_stack_index(DOUBLE_FAULT_IST_INDEX as u8);
+}
@@ -50,6 +50,13 @@ impl IdtEntry {
         self.offseth = (base >> 32) as u32;
     }
 
+    // Run the handler on stack `index` of the TSS' Interrupt Stack Table.
+    pub fn set_stack_index(&mut self, index: u8) {
+        assert!(index < 7, "only 7 interrupt stacks exist");
+        // 0 means "don't switch stacks", IST1 is stored as 1
+        self.ist = index + 1;
+    }
+
     pub fn set_func(&mut self, func: unsafe extern fn()) {
         self.set_flags(IdtFlags::PRESENT | IdtFlags::RING_0 | IdtFlags::INTERRUPT);
         self.set_offset(0x08, func as usize);
@@ -0,0 +1,147 @@
+// Hardware IRQ dispatching
+//
+// Every legacy IRQ line has a stub on vector IRQ_BASE + line which runs the
+// handlers registered for that line and acknowledges the interrupt afterwards,
+// so drivers never have to touch the IDT or send EOIs themselves. Work the
+// handlers deferred (see `sync::defer`) runs once the IRQ is acknowledged,
+// with interrupts enabled again.
+
+use sync::{self, IrqMutex};
+use core::intrinsics;
+
+use device::{apic, pic};
+use stats;
+use super::IDT;
+
+pub const IRQ_BASE: usize  = 32;
+pub const IRQ_COUNT: usize = 16;
+
+// How many handlers may share a single line.
+const MAX_SHARED: usize = 4;
+
+pub type IrqHandler = fn();
+
+static HANDLERS: IrqMutex<[[Option<IrqHandler>; MAX_SHARED]; IRQ_COUNT]> =
+    IrqMutex::new([[None; MAX_SHARED]; IRQ_COUNT]);
+
+// Add `handler` to the chain of `line` and unmask the line.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), &'static str> {
+    if line as usize >= IRQ_COUNT {
+        return Err("Invalid IRQ line.");
+    }
+    {
+        let mut handlers = HANDLERS.lock();
+        let chain = &mut handlers[line as usize];
+        if chain.iter().any(|slot| *slot == Some(handler)) {
+            return Err("Handler is already registered.");
+        }
+        match chain.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(handler),
+            None => return Err("Too many handlers share this IRQ line."),
+        }
+    }
+    unmask(line);
+    Ok(())
+}
+
+// Remove `handler` from the chain of `line`, masking the line if it was the last one.
+pub fn unregister_irq(line: u8, handler: IrqHandler) {
+    if line as usize >= IRQ_COUNT {
+        return;
+    }
+    let unused = {
+        let mut handlers = HANDLERS.lock();
+        let chain = &mut handlers[line as usize];
+        for slot in chain.iter_mut() {
+            if *slot == Some(handler) {
+                *slot = None;
+            }
+        }
+        chain.iter().all(|slot| slot.is_none())
+    };
+    if unused {
+        mask(line);
+    }
+}
+
+fn mask(line: u8) {
+    if apic::is_enabled() {
+        apic::mask_irq(line);
+    } else {
+        pic::mask_irq(line);
+    }
+}
+
+fn unmask(line: u8) {
+    if apic::is_enabled() {
+        apic::unmask_irq(line);
+    } else {
+        pic::unmask_irq(line);
+    }
+}
+
+// Only the 8259 produces spurious IRQ7/IRQ15, through the I/O APIC they are real.
+fn is_spurious(line: u8) -> bool {
+    !apic::is_enabled() && pic::is_spurious(line)
+}
+
+// Acknowledge IRQ `line` on whichever controller delivered it.
+fn send_eoi(line: u8) {
+    if apic::is_enabled() {
+        apic::eoi();
+    } else {
+        pic::send_eoi(line);
+    }
+}
+
+fn dispatch(line: u8) {
+    let vector = (IRQ_BASE + line as usize) as u8;
+    if is_spurious(line) {
+        stats::record_spurious(vector);
+        return;
+    }
+    stats::record(vector);
+    // copy the chain out, so handlers may (un)register from inside
+    let chain = HANDLERS.lock()[line as usize];
+    for handler in chain.iter() {
+        if let Some(handler) = *handler {
+            handler();
+        }
+    }
+    send_eoi(line);
+
+    if sync::has_pending() {
+        unsafe { sti!() };
+        sync::run_pending();
+        unsafe { cli!() };
+    }
+}
+
+pub fn init() {
+    interrupt!(irq0,  { dispatch(0);  });
+    interrupt!(irq1,  { dispatch(1);  });
+    interrupt!(irq2,  { dispatch(2);  });
+    interrupt!(irq3,  { dispatch(3);  });
+    interrupt!(irq4,  { dispatch(4);  });
+    interrupt!(irq5,  { dispatch(5);  });
+    interrupt!(irq6,  { dispatch(6);  });
+    interrupt!(irq7,  { dispatch(7);  });
+    interrupt!(irq8,  { dispatch(8);  });
+    interrupt!(irq9,  { dispatch(9);  });
+    interrupt!(irq10, { dispatch(10); });
+    interrupt!(irq11, { dispatch(11); });
+    interrupt!(irq12, { dispatch(12); });
+    interrupt!(irq13, { dispatch(13); });
+    interrupt!(irq14, { dispatch(14); });
+    interrupt!(irq15, { dispatch(15); });
+
+    let stubs: [unsafe extern fn(); IRQ_COUNT] = [
+        irq0, irq1, irq2,  irq3,  irq4,  irq5,  irq6,  irq7,
+        irq8, irq9, irq10, irq11, irq12, irq13, irq14, irq15,
+    ];
+
+    let mut idt = IDT.lock();
+    for (line, stub) in stubs.iter().enumerate() {
+        idt[IRQ_BASE + line].set_func(*stub);
+    }
+}
@@ -3,24 +3,48 @@
 #![feature(naked_functions)]
 #![feature(const_fn)]
 #![feature(core_intrinsics)]
+#![feature(repr_align)]
+#![feature(attr_literals)]
+
+// Interrupt stubs only save the integer registers, so nothing that may run
+// inside a handler is allowed to use SSE. Lazy FPU switching for code which
+// does want it lives in `fpu`.
+#[cfg(target_feature = "sse")]
+compile_error!("the kernel must be built without SSE, see x86_64-kurumi.json");
 
 #[macro_use]
 extern crate bitflags;
 extern crate spin;
+extern crate sync;
 
+#[macro_use]
+extern crate vga;
 extern crate device;
+extern crate memory;
 
 #[macro_use]
 pub mod macros;
+#[macro_use]
+mod console;
 pub mod idt;
+pub mod frame;
+pub mod exception;
+pub mod gdt;
+pub mod fpu;
+pub mod stats;
+pub mod debugger;
+pub mod gdbstub;
 mod dtables;
+mod irq;
 
 use spin::Mutex;
 use core::intrinsics;
 
 use idt::IdtEntry;
 use dtables::DescriptorTablePointer;
-use device::{pic, tty, keyboard};
+use device::apic;
+
+pub use irq::{register_irq, unregister_irq, IrqHandler};
 
 // The Interrupt Descriptor Table
 // The CPU will look at this table to find the appropriate interrupt handler.
@@ -34,25 +58,16 @@ pub fn init() {
 
     unsafe { dtables::lidt(&ptr) };
 
-    interrupt!(isr32, {
-        pic::send_eoi(32);
-    });
-
-    interrupt!(isr33, {
-        if let Some(c) = keyboard::read_char() {
-            tty::TTY_BUF.lock().input(c);
-        }
-        pic::send_eoi(33);
-    });
+    exception::init();
+    irq::init();
 
-    interrupt!(isr46, {
-        pic::send_eoi(46);
+    // The local APIC does not expect an EOI for its spurious vector.
+    interrupt!(isr255, {
+        stats::record_spurious(apic::SPURIOUS_VECTOR);
     });
 
     // IDT Table
-    IDT.lock()[32].set_func(isr32);
-    IDT.lock()[33].set_func(isr33);
-    IDT.lock()[46].set_func(isr46);
+    IDT.lock()[apic::SPURIOUS_VECTOR as usize].set_func(isr255);
 
     unsafe { sti!() }
 }
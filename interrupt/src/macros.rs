@@ -81,24 +81,108 @@ macro_rules! sti {
     ));
 }
 
+// `interrupt!(name, stack, regs, { ... })` defines an interrupt stub which
+// hands the interrupt stack frame and the saved general purpose registers to
+// the body as `&mut InterruptStackFrame` and `&mut SavedRegisters`. Changes
+// to either are written back when the stub returns.
+//
+// The CPU aligns the stack to 16 bytes before pushing the frame. The frame
+// (5 words) plus the 15 saved registers keep that alignment for the call.
 #[macro_export]
 macro_rules! interrupt {
-    ($name:ident, $body:expr) => {
+    ($name:ident, $stack:ident, $regs:ident, $body:expr) => {
 
         #[naked]
         unsafe extern fn $name() {
             #[inline(never)]
-            fn inner() {
+            #[allow(unused_variables)]
+            extern "C" fn inner($stack: &mut $crate::frame::InterruptStackFrame,
+                                $regs: &mut $crate::frame::SavedRegisters) {
                 $body
             }
 
             scratch_push!();
             preserved_push!();
-            cli!();
-            inner();
-            sti!();
+            asm!("mov rsi, rsp
+                  mov rdi, rsp
+                  add rdi, 15*8
+                  call $0"
+                  : : "i"(inner as extern "C" fn(&mut $crate::frame::InterruptStackFrame,
+                                                 &mut $crate::frame::SavedRegisters))
+                  : "rdi", "rsi" : "intel", "volatile");
+            preserved_pop!();
+            scratch_pop!();
+            iret!();
+
+            intrinsics::unreachable();
+        }
+    };
+    ($name:ident, $body:expr) => {
+        interrupt!($name, stack, regs, $body);
+    };
+}
+
+// Exception stubs work like `interrupt!` and pass the frame, the saved
+// registers and the error code, for exceptions that push one, to
+// `$crate::exception::handle`. The error code breaks the stack alignment,
+// so it is restored before the call.
+#[macro_export]
+macro_rules! exception {
+    ($name:ident, $vector:expr) => {
+
+        #[naked]
+        unsafe extern fn $name() {
+            extern "C" fn inner(stack: &mut $crate::frame::InterruptStackFrame,
+                                regs: &mut $crate::frame::SavedRegisters) {
+                $crate::exception::handle($vector, stack, regs, None);
+            }
+
+            scratch_push!();
+            preserved_push!();
+            asm!("mov rsi, rsp
+                  mov rdi, rsp
+                  add rdi, 15*8
+                  call $0"
+                  : : "i"(inner as extern "C" fn(&mut $crate::frame::InterruptStackFrame,
+                                                 &mut $crate::frame::SavedRegisters))
+                  : "rdi", "rsi" : "intel", "volatile");
+            preserved_pop!();
+            scratch_pop!();
+            iret!();
+
+            intrinsics::unreachable();
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! exception_error {
+    ($name:ident, $vector:expr) => {
+
+        #[naked]
+        unsafe extern fn $name() {
+            extern "C" fn inner(stack: &mut $crate::frame::InterruptStackFrame,
+                                regs: &mut $crate::frame::SavedRegisters,
+                                error_code: u64) {
+                $crate::exception::handle($vector, stack, regs, Some(error_code));
+            }
+
+            scratch_push!();
+            preserved_push!();
+            asm!("mov rdx, [rsp + 15*8]
+                  mov rsi, rsp
+                  mov rdi, rsp
+                  add rdi, 16*8
+                  sub rsp, 8
+                  call $0
+                  add rsp, 8"
+                  : : "i"(inner as extern "C" fn(&mut $crate::frame::InterruptStackFrame,
+                                                 &mut $crate::frame::SavedRegisters, u64))
+                  : "rdi", "rsi", "rdx" : "intel", "volatile");
             preserved_pop!();
             scratch_pop!();
+            // pop the error code, iretq does not expect it
+            asm!("add rsp, 8" : : : : "intel", "volatile");
             iret!();
 
             intrinsics::unreachable();
@@ -0,0 +1,102 @@
+// Interrupt and exception statistics
+//
+// Every vector that reaches one of our stubs is counted per CPU, together
+// with the PIT tick of its last occurrence. Spurious interrupts are counted
+// separately on the vector they arrived on, so a line which only ever fires
+// spuriously stands out. Use this to tell whether a device's IRQ fires at all.
+//
+// Exceptions are recorded here as well, NMI and machine checks included, so
+// no lock may be taken: one of those arriving while it is held would spin
+// forever. The counters are updated with atomic operations instead.
+
+use core::intrinsics;
+
+use device::acpi::MAX_CPUS;
+use device::{apic, pit};
+use exception;
+use irq::{IRQ_BASE, IRQ_COUNT};
+
+const VECTORS: usize = 256;
+
+// Only accessed through the atomic intrinsics
+static mut COUNTS: [[usize; VECTORS]; MAX_CPUS] = [[0; VECTORS]; MAX_CPUS];
+static mut SPURIOUS: [usize; VECTORS] = [0; VECTORS];
+// PIT tick of the last occurrence plus one, 0 if the vector never fired
+static mut LAST_TICK: [usize; VECTORS] = [0; VECTORS];
+
+// Index of the current CPU. Without the local APIC there is only the BSP.
+fn current_cpu() -> usize {
+    if apic::is_enabled() {
+        // APIC IDs need not be contiguous, fold the rare large one into the last slot
+        (apic::id() as usize).min(MAX_CPUS - 1)
+    } else {
+        0
+    }
+}
+
+// Count an occurrence of `vector` on the current CPU.
+pub fn record(vector: u8) {
+    let cpu = current_cpu();
+    unsafe {
+        intrinsics::atomic_xadd(&mut COUNTS[cpu][vector as usize], 1);
+        intrinsics::atomic_store(&mut LAST_TICK[vector as usize], pit::ticks() + 1);
+    }
+}
+
+// Count a spurious interrupt on `vector`, in addition to `record`.
+pub fn record_spurious(vector: u8) {
+    record(vector);
+    unsafe { intrinsics::atomic_xadd(&mut SPURIOUS[vector as usize], 1) };
+}
+
+// Occurrences of `vector` on all CPUs.
+pub fn count(vector: u8) -> u64 {
+    (0..MAX_CPUS).map(|cpu| count_on(cpu, vector)).sum()
+}
+
+// Occurrences of `vector` on CPU `cpu`.
+pub fn count_on(cpu: usize, vector: u8) -> u64 {
+    if cpu >= MAX_CPUS {
+        return 0;
+    }
+    unsafe { intrinsics::atomic_load(&COUNTS[cpu][vector as usize]) as u64 }
+}
+
+// How many occurrences of `vector` were spurious.
+pub fn spurious(vector: u8) -> u64 {
+    unsafe { intrinsics::atomic_load(&SPURIOUS[vector as usize]) as u64 }
+}
+
+// PIT tick of the last occurrence of `vector`, None if it never happened.
+pub fn last_tick(vector: u8) -> Option<usize> {
+    match unsafe { intrinsics::atomic_load(&LAST_TICK[vector as usize]) } {
+        0 => None,
+        tick => Some(tick - 1),
+    }
+}
+
+fn vector_name(vector: usize) -> &'static str {
+    match vector {
+        0 ... 31 => exception::name(vector as u8),
+        _ if vector == apic::SPURIOUS_VECTOR as usize => "APIC Spurious",
+        _ => "",
+    }
+}
+
+// Print every vector seen so far.
+pub fn print() {
+    kprintln!("VEC  NAME                            COUNT   SPURIOUS  LAST TICK");
+    for vector in 0..VECTORS {
+        let total = count(vector as u8);
+        if total == 0 {
+            continue;
+        }
+        if vector >= IRQ_BASE && vector < IRQ_BASE + IRQ_COUNT {
+            kprint!("{:>3}  IRQ {:<26}", vector, vector - IRQ_BASE);
+        } else {
+            kprint!("{:>3}  {:<30}", vector, vector_name(vector));
+        }
+        kprintln!(" {:>7} {:>10} {:>10}", total, spurious(vector as u8),
+                  last_tick(vector as u8).unwrap_or(0));
+    }
+}
@@ -0,0 +1,406 @@
+use alloc::Vec;
+use core::ops::Range;
+use core::{ptr, slice};
+use sync::IrqMutex;
+
+use super::{with_controller, Frame, FrameAllocator, PhysicalAddress, VirtualAddress};
+use super::{EntryFlags, PAGE_SIZE, SCRATCH_PAGE, WRITABLE, COPY_ON_WRITE};
+use super::paging::{physical_memory_offset, InactivePageTable, Mapper, Page};
+
+// The part of the address space that belongs to an `AddressSpace`, the lower
+// half without the first 512 GiB. Everything else is the kernel's and shared
+// by all address spaces.
+pub const USER_START: VirtualAddress = 0o_001_000_000_000_0000;
+pub const USER_END: VirtualAddress = 0o_400_000_000_000_0000;
+
+// What the pages of a VMA are mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    // zeroed frames, freed on unmap
+    Anonymous,
+    // the physical range starting at the given address, e.g. device memory
+    Physical(PhysicalAddress),
+    // frames filled from `file` at `offset` by the file loader, freed on unmap
+    File { file: usize, offset: usize },
+}
+
+// Reads `file` from `offset` into `buffer`. The part of `buffer` past the end
+// of the file is left zeroed.
+pub type FileLoader = fn(file: usize, offset: usize, buffer: &mut [u8]) -> Result<(), &'static str>;
+
+static FILE_LOADER: IrqMutex<Option<FileLoader>> = IrqMutex::new(None);
+
+// Set the loader file backed areas are filled with. It is called without the
+// memory controller held and may allocate.
+pub fn set_file_loader(loader: Option<FileLoader>) {
+    *FILE_LOADER.lock() = loader;
+}
+
+// A virtual memory area, the range [start, end) mapped with the same flags
+// and backing.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub flags: EntryFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    fn pages(&self) -> usize {
+        (self.end - self.start) / PAGE_SIZE
+    }
+
+    // Whether the frames are allocated for the area. Physical ranges are not
+    // ours to free or copy.
+    fn owns_frames(&self) -> bool {
+        match self.backing {
+            Backing::Physical(_) => false,
+            _ => true,
+        }
+    }
+
+    // The backing of the part of this area that starts at `address`.
+    fn backing_at(&self, address: VirtualAddress) -> Backing {
+        let offset = address - self.start;
+        match self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Physical(start) => Backing::Physical(start + offset),
+            Backing::File { file, offset: start } => Backing::File { file: file, offset: start + offset },
+        }
+    }
+}
+
+// A page table and the VMAs mapped in it. The kernel part is shared with the
+// active table at creation. Kernel mappings made later below P4 entries that
+// did not exist at that time are not seen by the address space.
+//
+// The page table is edited through `ActivePageTable::with` unless it is the
+// active one. VMAs are kept sorted and never overlap. Frames of anonymous and
+// file backed areas may be shared with clones, see `clone_cow`.
+pub struct AddressSpace {
+    table: InactivePageTable,
+    vmas: Vec<Vma>,
+}
+
+fn is_user_range(start: VirtualAddress, size: usize) -> bool {
+    start % PAGE_SIZE == 0 && size % PAGE_SIZE == 0 && size > 0
+        && start >= USER_START && start < USER_END && size <= USER_END - start
+}
+
+// The P4 entries covering user space, each covers 512 GiB.
+fn user_p4_indices() -> Range<usize> {
+    (USER_START >> 39)..((USER_END - 1) >> 39) + 1
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let user = user_p4_indices();
+        // entry 511 is the recursive mapping of the table, if it has one
+        let kernel_entries = (0..511).filter(move |&index| index < user.start || index >= user.end);
+        let table = with_controller(|controller| controller.new_table(kernel_entries))?;
+        Ok(AddressSpace {
+            table: table,
+            vmas: Vec::new(),
+        })
+    }
+
+    pub fn table(&self) -> &InactivePageTable {
+        &self.table
+    }
+
+    // Make this the active address space. Returns the table that was active,
+    // switch back to it with `memory::switch_table` before this is dropped.
+    pub fn activate(&self) -> InactivePageTable {
+        super::switch_table(&self.table)
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    // The VMA containing `address`.
+    pub fn find(&self, address: VirtualAddress) -> Option<&Vma> {
+        self.vmas.iter().find(|vma| vma.start <= address && address < vma.end)
+    }
+
+    // The physical address `address` is mapped to.
+    pub fn translate(&mut self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        let table = &mut self.table;
+        with_controller(|controller| controller.with_table(table, |mapper, _| {
+            mapper.translate(address)
+        }))
+    }
+
+    // The lowest free range of `size` bytes.
+    pub fn find_free_range(&self, size: usize) -> Option<VirtualAddress> {
+        if size > USER_END - USER_START {
+            return None;
+        }
+        let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let mut start = USER_START;
+        for vma in self.vmas.iter() {
+            if vma.start - start >= size {
+                break;
+            }
+            start = vma.end;
+        }
+        if is_user_range(start, size) { Some(start) } else { None }
+    }
+
+    // Map [start, start + size) with `flags`. Frames of anonymous and file
+    // backed areas are allocated and zeroed right away, the latter are then
+    // filled through the file loader.
+    pub fn map(&mut self, start: VirtualAddress, size: usize, flags: EntryFlags,
+               backing: Backing) -> Result<(), &'static str> {
+        if !is_user_range(start, size) {
+            return Err("Range is not page aligned or outside of user space.");
+        }
+        let end = start + size;
+        if self.vmas.iter().any(|vma| start < vma.end && vma.start < end) {
+            return Err("Range overlaps a mapped area.");
+        }
+        let loader = match backing {
+            Backing::File { .. } => Some((*FILE_LOADER.lock()).ok_or("No file loader is set.")?),
+            _ => None,
+        };
+        let vma = Vma { start: start, end: end, flags: flags, backing: backing };
+        // make room before taking the memory controller, it must not allocate
+        let index = self.vmas.iter().position(|vma| vma.start > start).unwrap_or(self.vmas.len());
+        self.vmas.reserve(1);
+
+        with_controller(|controller| controller.with_table(&mut self.table, |mapper, allocator| {
+            map_vma(&vma, mapper, allocator)
+        }))?;
+        if let Some(loader) = loader {
+            if let Err(error) = self.load_file(&vma, loader) {
+                let table = &mut self.table;
+                with_controller(|controller| controller.with_table(table, |mapper, allocator| {
+                    unmap_vma(&vma, mapper, allocator)
+                }));
+                return Err(error);
+            }
+        }
+        self.vmas.insert(index, vma);
+        Ok(())
+    }
+
+    // Fill the frames of the file backed `vma` page by page. The loader runs
+    // without the memory controller, the page is copied in afterwards.
+    fn load_file(&mut self, vma: &Vma, loader: FileLoader) -> Result<(), &'static str> {
+        let (file, offset) = match vma.backing {
+            Backing::File { file, offset } => (file, offset),
+            _ => return Ok(()),
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.resize(PAGE_SIZE, 0);
+        let start_page = Page::containing_address(vma.start);
+        for index in 0..vma.pages() {
+            for byte in buffer.iter_mut() {
+                *byte = 0;
+            }
+            loader(file, offset + index * PAGE_SIZE, &mut buffer[..])?;
+
+            let page = start_page + index;
+            let buffer = &buffer;
+            let table = &mut self.table;
+            with_controller(|controller| controller.with_table(table, |mapper, allocator| {
+                let (frame, _) = mapper.entry(page).expect("page of a VMA is not mapped");
+                with_frame(&frame, mapper, allocator, |bytes| bytes.copy_from_slice(buffer));
+            }));
+        }
+        Ok(())
+    }
+
+    // Unmap [start, start + size). Areas only partly inside are split, parts
+    // of the range that are not mapped are skipped.
+    pub fn unmap(&mut self, start: VirtualAddress, size: usize) -> Result<(), &'static str> {
+        let (first, last) = self.split_range(start, size)?;
+        let removed: Vec<Vma> = self.vmas.drain(first..last).collect();
+        with_controller(|controller| controller.with_table(&mut self.table, |mapper, allocator| {
+            for vma in removed.iter() {
+                unmap_vma(vma, mapper, allocator);
+            }
+        }));
+        Ok(())
+    }
+
+    // Change the flags of the mapped pages in [start, start + size).
+    pub fn protect(&mut self, start: VirtualAddress, size: usize,
+                   flags: EntryFlags) -> Result<(), &'static str> {
+        let (first, last) = self.split_range(start, size)?;
+        let vmas = &mut self.vmas[first..last];
+        let table = &mut self.table;
+        for vma in vmas.iter_mut() {
+            vma.flags = flags;
+        }
+        with_controller(|controller| controller.with_table(table, |mapper, allocator| {
+            for vma in vmas.iter() {
+                let start_page = Page::containing_address(vma.start);
+                for page in (0..vma.pages()).map(|offset| start_page + offset) {
+                    let (frame, _) = mapper.entry(page).expect("page of a VMA is not mapped");
+                    // shared frames stay read-only until the first write
+                    let shared = vma.owns_frames() && allocator.is_shared(&frame);
+                    let flags = if flags.contains(WRITABLE) && shared {
+                        (flags - WRITABLE) | COPY_ON_WRITE
+                    } else {
+                        flags
+                    };
+                    mapper.set_flags(page, flags);
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    // A copy of this address space that shares all frames with it. Writable
+    // pages become read-only and copy on write in both, the page fault handler
+    // gives a page a frame of its own on the first write.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, &'static str> {
+        let mut child = AddressSpace::new()?;
+        child.vmas = self.vmas.clone();
+
+        // collect the entries first, only one inactive table can be edited at a time
+        let pages = self.vmas.iter().map(|vma| vma.pages()).sum();
+        let mut entries: Vec<(Page, Frame, EntryFlags)> = Vec::with_capacity(pages);
+        {
+            let vmas = &self.vmas;
+            let table = &mut self.table;
+            let entries = &mut entries;
+            with_controller(|controller| controller.with_table(table, |mapper, allocator| {
+                for vma in vmas.iter() {
+                    let start_page = Page::containing_address(vma.start);
+                    for page in (0..vma.pages()).map(|offset| start_page + offset) {
+                        let (frame, mut flags) = mapper.entry(page).expect("page of a VMA is not mapped");
+                        if vma.owns_frames() {
+                            if flags.contains(WRITABLE) {
+                                flags = (flags - WRITABLE) | COPY_ON_WRITE;
+                                mapper.set_flags(page, flags);
+                            }
+                            allocator.share(&frame);
+                        }
+                        entries.push((page, frame, flags));
+                    }
+                }
+            }));
+        }
+        {
+            let table = &mut child.table;
+            let entries = &mut entries;
+            with_controller(|controller| controller.with_table(table, |mapper, allocator| {
+                for (page, frame, flags) in entries.drain(..) {
+                    mapper.map_to(page, frame, flags, allocator);
+                }
+            }));
+        }
+        Ok(child)
+    }
+
+    // Split the VMAs at the bounds of [start, start + size) and return the
+    // indices of the VMAs inside.
+    fn split_range(&mut self, start: VirtualAddress,
+                   size: usize) -> Result<(usize, usize), &'static str> {
+        if !is_user_range(start, size) {
+            return Err("Range is not page aligned or outside of user space.");
+        }
+        let end = start + size;
+        self.split_at(start);
+        self.split_at(end);
+        let first = self.vmas.iter().position(|vma| vma.start >= start).unwrap_or(self.vmas.len());
+        let last = self.vmas.iter().position(|vma| vma.start >= end).unwrap_or(self.vmas.len());
+        Ok((first, last))
+    }
+
+    // Split the VMA containing `address` in two, unless it starts there.
+    fn split_at(&mut self, address: VirtualAddress) {
+        let index = match self.vmas.iter().position(|vma| vma.start < address && address < vma.end) {
+            Some(index) => index,
+            None => return,
+        };
+        let upper = Vma {
+            start: address,
+            end: self.vmas[index].end,
+            flags: self.vmas[index].flags,
+            backing: self.vmas[index].backing_at(address),
+        };
+        self.vmas[index].end = address;
+        self.vmas.insert(index + 1, upper);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.table.is_active(), "the active address space cannot be dropped");
+        let vmas = &self.vmas;
+        let table = &mut self.table;
+        with_controller(|controller| {
+            controller.with_table(table, |mapper, allocator| {
+                for vma in vmas.iter() {
+                    unmap_vma(vma, mapper, allocator);
+                }
+                for index in user_p4_indices() {
+                    mapper.free_tables(index, allocator);
+                }
+            });
+            controller.frame_allocator.deallocate_frame(table.p4_frame().clone());
+        });
+    }
+}
+
+// Map the pages of `vma`. On failure nothing stays mapped.
+fn map_vma<A>(vma: &Vma, mapper: &mut Mapper, allocator: &mut A)
+    -> Result<(), &'static str> where A: FrameAllocator {
+    let start_page = Page::containing_address(vma.start);
+    for done in 0..vma.pages() {
+        let page = start_page + done;
+        let frame = match vma.backing {
+            Backing::Physical(start) => Frame::containing_address(start + done * PAGE_SIZE),
+            _ => match allocator.allocate_frame() {
+                Some(frame) => {
+                    zero_frame(&frame, mapper, allocator);
+                    frame
+                }
+                None => {
+                    unmap_vma(&Vma { end: page.start_address(), ..*vma }, mapper, allocator);
+                    return Err("Out of physical memory.");
+                }
+            },
+        };
+        mapper.map_to(page, frame, vma.flags, allocator);
+    }
+    Ok(())
+}
+
+fn unmap_vma<A>(vma: &Vma, mapper: &mut Mapper, allocator: &mut A) where A: FrameAllocator {
+    let start_page = Page::containing_address(vma.start);
+    for page in (0..vma.pages()).map(|offset| start_page + offset) {
+        if vma.owns_frames() {
+            mapper.unmap_and_free(page, allocator);
+        } else {
+            mapper.unmap(page, allocator);
+        }
+    }
+}
+
+// Zero `frame`.
+fn zero_frame<A>(frame: &Frame, mapper: &mut Mapper, allocator: &mut A) where A: FrameAllocator {
+    with_frame(frame, mapper, allocator, |bytes| unsafe {
+        ptr::write_bytes(bytes.as_mut_ptr(), 0, PAGE_SIZE)
+    });
+}
+
+// Run `f` on the contents of `frame`, reached through the mapping of all
+// physical memory, or else the scratch page. That lies in the kernel part,
+// whose page tables are shared, so mapping it through a mapper for another
+// table maps it in the active one as well.
+fn with_frame<A, F>(frame: &Frame, mapper: &mut Mapper, allocator: &mut A, f: F)
+    where A: FrameAllocator, F: FnOnce(&mut [u8]) {
+    if let Some(offset) = physical_memory_offset() {
+        f(unsafe { slice::from_raw_parts_mut((frame.start_address() + offset) as *mut u8, PAGE_SIZE) });
+        return;
+    }
+    let page = Page::containing_address(SCRATCH_PAGE);
+    mapper.map_to(page, frame.clone(), WRITABLE, allocator);
+    f(unsafe { slice::from_raw_parts_mut(SCRATCH_PAGE as *mut u8, PAGE_SIZE) });
+    mapper.unmap(page, allocator);
+}
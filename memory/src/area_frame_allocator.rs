@@ -32,6 +32,18 @@ impl AreaFrameAllocator {
         allocator
     }
 
+    pub fn areas(&self) -> MemoryAreaIter {
+        self.areas.clone()
+    }
+
+    // Returns true if `frame` was handed out already or holds the kernel or
+    // the multiboot information structure.
+    pub fn is_used(&self, frame: &Frame) -> bool {
+        *frame < self.next_free_frame
+            || (*frame >= self.kernel_start && *frame <= self.kernel_end)
+            || (*frame >= self.multiboot_start && *frame <= self.multiboot_end)
+    }
+
     fn choose_next_area(&mut self) {
         self.current_area = self.areas
             .clone()
@@ -84,7 +96,7 @@ impl FrameAllocator for AreaFrameAllocator {
         }
     }
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
-    }
+    // Only used at boot, e.g. when unmapping frees an empty page table. The
+    // frame is leaked, this allocator never hands out a frame twice.
+    fn deallocate_frame(&mut self, _frame: Frame) {}
 }
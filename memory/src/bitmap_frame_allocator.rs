@@ -0,0 +1,191 @@
+use core::slice;
+
+use super::{Frame, FrameAllocator, AreaFrameAllocator, FRAME_BITMAP_START, FRAME_REFCOUNT_START};
+use super::{PhysicalAddress, PAGE_SIZE};
+use super::paging::{self, Page, ActivePageTable};
+
+const BITS: usize = 64;
+
+// A frame allocator that tracks every frame of the usable memory areas with
+// one bit, set if the frame is in use. Frames are never lost, unlike with the
+// `AreaFrameAllocator` it is bootstrapped from.
+//
+// The bitmap covers all frames from 0 up to the last usable one and lives at
+// `FRAME_BITMAP_START`. Frames outside of the memory areas stay marked as used.
+//
+// Frames shared copy-on-write between address spaces have a reference count,
+// kept at `FRAME_REFCOUNT_START`. Deallocating a shared frame only drops one
+// reference.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    refcounts: &'static mut [u16],  // mappings of a shared frame, 0 if not shared
+    frame_count: usize,    // frames covered by the bitmap
+    total: usize,          // usable frames
+    free: usize,
+    next: usize,           // word to start searching from
+}
+
+impl BitmapFrameAllocator {
+    // Map the bitmap with frames from `area_allocator` and take over from it.
+    // Every frame it handed out so far, the kernel and the multiboot
+    // information structure are marked as used.
+    pub fn new(mut area_allocator: AreaFrameAllocator,
+               active_table: &mut ActivePageTable) -> BitmapFrameAllocator {
+        let frame_count = area_allocator.areas()
+            .map(|area| Frame::containing_address((area.base_addr + area.length - 1) as usize).number + 1)
+            .max()
+            .expect("no usable memory areas");
+        let words = (frame_count + BITS - 1) / BITS;
+
+        {
+            let mut map = |start: usize, size: usize| {
+                let start_page = Page::containing_address(start);
+                let end_page = Page::containing_address(start + size - 1);
+                for page in Page::range_inclusive(start_page, end_page) {
+                    active_table.map(page, paging::WRITABLE, &mut area_allocator);
+                }
+            };
+            map(FRAME_BITMAP_START, words * 8);
+            map(FRAME_REFCOUNT_START, frame_count * 2);
+        }
+        let bitmap = unsafe { slice::from_raw_parts_mut(FRAME_BITMAP_START as *mut u64, words) };
+        let refcounts = unsafe {
+            slice::from_raw_parts_mut(FRAME_REFCOUNT_START as *mut u16, frame_count)
+        };
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: bitmap,
+            refcounts: refcounts,
+            frame_count: frame_count,
+            total: 0,
+            free: 0,
+            next: 0,
+        };
+        for word in allocator.bitmap.iter_mut() {
+            *word = !0;
+        }
+        for count in allocator.refcounts.iter_mut() {
+            *count = 0;
+        }
+        for area in area_allocator.areas() {
+            let start = Frame::containing_address(area.base_addr as usize);
+            let end = Frame::containing_address((area.base_addr + area.length - 1) as usize);
+            for frame in Frame::range_inclusive(start, end) {
+                allocator.total += 1;
+                if !area_allocator.is_used(&frame) {
+                    allocator.set_free(frame.number);
+                    allocator.free += 1;
+                }
+            }
+        }
+        allocator
+    }
+
+    fn is_free(&self, number: usize) -> bool {
+        self.bitmap[number / BITS] & (1 << (number % BITS)) == 0
+    }
+
+    fn set_free(&mut self, number: usize) {
+        self.bitmap[number / BITS] &= !(1 << (number % BITS));
+    }
+
+    fn set_used(&mut self, number: usize) {
+        self.bitmap[number / BITS] |= 1 << (number % BITS);
+    }
+
+    // Allocate `count` physically contiguous frames, the first of which is a
+    // multiple of `align` frames. Returns the first frame.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<Frame> {
+        assert!(count > 0 && align.is_power_of_two());
+        if count > self.free {
+            return None;
+        }
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            match (start..start + count).find(|number| !self.is_free(*number)) {
+                // continue after the frame in the way
+                Some(used) => start = (used + align) & !(align - 1),
+                None => {
+                    for number in start..start + count {
+                        self.set_used(number);
+                    }
+                    self.free -= count;
+                    return Some(Frame { number: start });
+                }
+            }
+        }
+        None
+    }
+
+    // Free `count` frames starting at `frame`.
+    pub fn deallocate_contiguous(&mut self, frame: Frame, count: usize) {
+        for number in frame.number..frame.number + count {
+            self.deallocate_frame(Frame { number: number });
+        }
+    }
+
+    // Add a mapping of the allocated `frame`, which is shared from now on.
+    pub fn share(&mut self, frame: &Frame) {
+        assert!(frame.number < self.frame_count && !self.is_free(frame.number),
+                "frame {:#x} is not allocated", frame.start_address());
+        let count = &mut self.refcounts[frame.number];
+        *count = match *count {
+            0 => 2,
+            count => count.checked_add(1).expect("too many mappings of a shared frame"),
+        };
+    }
+
+    // Frames beyond the bitmap, e.g. device memory, are never shared.
+    pub fn is_shared(&self, frame: &Frame) -> bool {
+        frame.number < self.frame_count && self.refcounts[frame.number] != 0
+    }
+
+    // The end of the last usable frame.
+    pub fn physical_end(&self) -> PhysicalAddress {
+        self.frame_count * PAGE_SIZE
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let index = (self.next + i) % words;
+            let word = self.bitmap[index];
+            if word != !0 {
+                // bits past the last frame are never cleared
+                let number = index * BITS + (!word).trailing_zeros() as usize;
+                self.set_used(number);
+                self.free -= 1;
+                self.next = index;
+                return Some(Frame { number: number });
+            }
+        }
+        None
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(frame.number < self.frame_count && !self.is_free(frame.number),
+                "frame {:#x} is not allocated", frame.start_address());
+        // a shared frame is freed with its last mapping
+        let count = self.refcounts[frame.number];
+        if count != 0 {
+            self.refcounts[frame.number] = if count == 2 { 0 } else { count - 1 };
+            return;
+        }
+        self.set_free(frame.number);
+        self.free += 1;
+    }
+}
@@ -1,6 +1,10 @@
 use alloc::heap::{Alloc, AllocErr, Layout};
 
 use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::Heap;
+use sync::IrqMutex;
+
+use super::PAGE_SIZE;
 
 // A simple allocator that allocates memory linearly and ignores freed memory.
 #[derive(Debug)]
@@ -64,3 +68,91 @@ pub fn align_down(addr: usize, align: usize) -> usize {
 pub fn align_up(addr: usize, align: usize) -> usize {
     align_down(addr + align - 1, align)
 }
+
+// Maps the pages for `size` more bytes of heap at `start`. Returns false if
+// that was not possible.
+pub type GrowHeap = fn(start: usize, size: usize) -> bool;
+
+// Grow by at least this much, so small allocations don't map page by page.
+const GROW_STEP: usize = 64 * 1024;
+
+struct HeapState {
+    heap: Heap,
+    max_size: usize,
+    grow: Option<GrowHeap>,
+}
+
+// A linked list heap which grows in place: when an allocation fails, the
+// pages right after the heap's end are mapped through `grow` and added to the
+// heap, until it reaches `max_size`.
+pub struct GrowableHeap {
+    state: IrqMutex<HeapState>,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> GrowableHeap {
+        GrowableHeap {
+            state: IrqMutex::new(HeapState { heap: Heap::empty(), max_size: 0, grow: None }),
+        }
+    }
+
+    // `size` bytes at `start` must be mapped already, `start + max_size` is
+    // the limit for growing.
+    pub unsafe fn init(&self, start: usize, size: usize, max_size: usize, grow: GrowHeap) {
+        assert!(size <= max_size && (start + size) % PAGE_SIZE == 0,
+                "the heap must end on a page boundary");
+        let mut state = self.state.lock();
+        state.heap.init(start, size);
+        state.max_size = max_size;
+        state.grow = Some(grow);
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().heap.size()
+    }
+}
+
+impl HeapState {
+    // Map enough memory for `layout` after the end of the heap.
+    fn grow_for(&mut self, layout: &Layout) -> bool {
+        let grow = match self.grow {
+            Some(grow) => grow,
+            None => return false,
+        };
+        // the new memory may start with alignment padding
+        let needed = layout.size() + layout.align();
+        let available = self.max_size - self.heap.size();
+        let by = align_up(needed.max(GROW_STEP), PAGE_SIZE).min(available);
+        if by < needed {
+            return false;
+        }
+        let top = self.heap.bottom() + self.heap.size();
+        if !grow(top, by) {
+            return false;
+        }
+        unsafe { self.heap.extend(by) };
+        true
+    }
+}
+
+unsafe impl<'a> Alloc for &'a GrowableHeap {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let mut state = self.state.lock();
+        if let Ok(ptr) = state.heap.allocate_first_fit(layout.clone()) {
+            return Ok(ptr);
+        }
+        if state.grow_for(&layout) {
+            state.heap.allocate_first_fit(layout)
+        } else {
+            Err(AllocErr::Exhausted { request: layout })
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.state.lock().heap.deallocate(ptr, layout)
+    }
+
+    fn oom(&mut self, _: AllocErr) -> ! {
+        panic!("Out of memory");
+    }
+}
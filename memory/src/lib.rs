@@ -1,4 +1,5 @@
 #![no_std]
+#![feature(asm)]
 #![feature(const_fn)]
 #![feature(ptr_internals)]
 #![feature(alloc)]
@@ -12,25 +13,58 @@ extern crate bitflags;
 extern crate vga;
 extern crate x86_64;
 extern crate multiboot2;
+extern crate linked_list_allocator;
+extern crate sync;
 
+use core::ptr;
 use multiboot2::BootInformation;
+use sync::IrqMutex;
 
+mod address_space;
 mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod page_fault;
 mod paging;
 pub mod frame;
 pub mod heap_allocator;
+pub mod slab_allocator;
 mod stack_allocator;
 
+pub use address_space::{AddressSpace, Backing, FileLoader, Vma, USER_START, USER_END};
+pub use address_space::set_file_loader;
 pub use area_frame_allocator::AreaFrameAllocator;
-pub use paging::remap_the_kernel;
+pub use bitmap_frame_allocator::BitmapFrameAllocator;
+pub use paging::{remap_the_kernel, InactivePageTable};
+pub use page_fault::PageFault;
 pub use stack_allocator::Stack;
 pub use frame::{FrameAllocator, Frame, FrameIter};
-use paging::PhysicalAddress;
+pub use paging::{PhysicalAddress, VirtualAddress, PageSize, supports_1gib_pages};
+pub use paging::{physical_memory_offset, PHYSICAL_MEMORY_OFFSET};
+pub use paging::{EntryFlags, PRESENT, WRITABLE, USER_ACCESSIBLE, WRITE_THROUGH, NO_CACHE, NO_EXECUTE};
+pub use paging::COPY_ON_WRITE;
 
 pub const PAGE_SIZE: usize = 4096;
 
-pub fn init(boot_info: &BootInformation, heap_start: usize, heap_size: usize) -> MemoryController {
-    //assert_has_not_been_called!("memory::init must be called only once");
+// Where the bitmap of the frame allocator is mapped, at 2 GiB.
+const FRAME_BITMAP_START: usize = 0o_000_002_000_000_0000;
+
+// Where the reference counts of shared frames are mapped, at 2.25 GiB.
+const FRAME_REFCOUNT_START: usize = 0o_000_002_200_000_0000;
+
+// Pages for editing other page tables, at 2.5 GiB. They are in the first
+// 512 GiB, which every address space shares with the kernel.
+const TEMPORARY_PAGE: usize = 0o_000_002_400_000_0000;
+const SCRATCH_PAGE: usize = TEMPORARY_PAGE + PAGE_SIZE;
+
+// The memory controller, see `with_controller`. Code holding the lock must
+// not allocate from the heap, growing the heap needs the controller as well.
+static MEMORY_CONTROLLER: IrqMutex<Option<MemoryController>> = IrqMutex::new(None);
+
+// Maps `heap_size` bytes at `heap_start`. The heap may grow up to
+// `heap_max_size`, the stacks are placed behind that.
+pub fn init(boot_info: &BootInformation, heap_start: usize, heap_size: usize,
+            heap_max_size: usize) {
+    assert!(MEMORY_CONTROLLER.lock().is_none(), "memory::init must be called only once");
 
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
     let elf_sections_tag = boot_info
@@ -61,7 +95,7 @@ pub fn init(boot_info: &BootInformation, heap_start: usize, heap_size: usize) ->
         boot_info.end_address()
     );
 
-    let mut frame_allocator = AreaFrameAllocator::new(
+    let mut area_allocator = AreaFrameAllocator::new(
         kernel_start as usize,
         kernel_end as usize,
         boot_info.start_address(),
@@ -69,7 +103,12 @@ pub fn init(boot_info: &BootInformation, heap_start: usize, heap_size: usize) ->
         memory_map_tag.memory_areas(),
     );
 
-    let mut active_table = paging::remap_the_kernel(&mut frame_allocator, boot_info);
+    let mut active_table = paging::remap_the_kernel(&mut area_allocator, boot_info);
+    let mut frame_allocator = BitmapFrameAllocator::new(area_allocator, &mut active_table);
+    if cfg!(feature = "phys_offset") {
+        let end = frame_allocator.physical_end();
+        paging::map_physical_memory(&mut active_table, end, &mut frame_allocator);
+    }
 
     use self::paging::Page;
 
@@ -80,24 +119,155 @@ pub fn init(boot_info: &BootInformation, heap_start: usize, heap_size: usize) ->
         active_table.map(page, paging::WRITABLE, &mut frame_allocator);
     }
 
+    // other tables are reached through the physical memory mapping if there is one
+    let temporary_page = match paging::physical_memory_offset() {
+        Some(_) => None,
+        None => Some(paging::TemporaryPage::new(Page::containing_address(TEMPORARY_PAGE),
+                                                &mut frame_allocator)),
+    };
+
     let stack_allocator = {
-        let stack_alloc_start = heap_end_page + 1;
+        let stack_alloc_start = Page::containing_address(heap_start + heap_max_size) + 1;
         let stack_alloc_end = stack_alloc_start + 100;
         let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
         stack_allocator::StackAllocator::new(stack_alloc_range)
     };
 
-    MemoryController {
+    *MEMORY_CONTROLLER.lock() = Some(MemoryController {
         active_table: active_table,
         frame_allocator: frame_allocator,
         stack_allocator: stack_allocator,
+        temporary_page: temporary_page,
+        regions: page_fault::Regions::new(),
+    });
+}
+
+// Run `f` with the memory controller. Interrupts are disabled meanwhile and
+// `f` must not allocate from the heap.
+pub fn with_controller<F, R>(f: F) -> R where F: FnOnce(&mut MemoryController) -> R {
+    let mut controller = MEMORY_CONTROLLER.lock();
+    f(controller.as_mut().expect("memory::init has not been called"))
+}
+
+pub fn allocate_frame() -> Option<Frame> {
+    with_controller(|controller| controller.frame_allocator.allocate_frame())
+}
+
+pub fn deallocate_frame(frame: Frame) {
+    with_controller(|controller| controller.frame_allocator.deallocate_frame(frame))
+}
+
+pub fn allocate_frames(count: usize, align: usize) -> Option<Frame> {
+    with_controller(|controller| controller.allocate_frames(count, align))
+}
+
+pub fn deallocate_frames(frame: Frame, count: usize) {
+    with_controller(|controller| controller.deallocate_frames(frame, count))
+}
+
+pub fn free_frames() -> usize {
+    with_controller(|controller| controller.free_frames())
+}
+
+pub fn used_frames() -> usize {
+    with_controller(|controller| controller.used_frames())
+}
+
+pub fn map_pages(start: VirtualAddress, size: usize, flags: EntryFlags) -> Result<(), &'static str> {
+    with_controller(|controller| controller.map_pages(start, size, flags))
+}
+
+pub fn unmap_pages(start: VirtualAddress, size: usize) {
+    with_controller(|controller| controller.unmap_pages(start, size))
+}
+
+pub fn map_physical(virtual_address: VirtualAddress, physical: PhysicalAddress,
+                    size: usize, flags: EntryFlags) {
+    with_controller(|controller| controller.map_physical(virtual_address, physical, size, flags))
+}
+
+pub fn identity_map(start: PhysicalAddress, size: usize,
+                    flags: EntryFlags) -> Result<(), &'static str> {
+    with_controller(|controller| controller.identity_map(start, size, flags))
+}
+
+// Identity map device registers or firmware tables at [start, start + size),
+// uncached. Fails if a page of the range is in use for something else.
+pub fn map_mmio(start: PhysicalAddress, size: usize) -> Result<(), &'static str> {
+    identity_map(start, size, WRITABLE | NO_CACHE)
+}
+
+// Load `table` into CR3 and return the table that was active.
+pub fn switch_table(table: &InactivePageTable) -> InactivePageTable {
+    with_controller(|controller| controller.active_table.switch_to(table))
+}
+
+pub fn alloc_stack(size_in_pages: usize) -> Option<Stack> {
+    with_controller(|controller| controller.alloc_stack(size_in_pages))
+}
+
+// Reserve [start, start + size) without mapping it. Each page is mapped to a
+// zeroed frame with `flags` when it is first touched.
+pub fn reserve(start: VirtualAddress, size: usize, flags: EntryFlags) -> Result<(), &'static str> {
+    with_controller(|controller| controller.regions.reserve(start, size, flags))
+}
+
+// Release the region reserved at `start` and free the pages faulted in.
+pub fn release(start: VirtualAddress) {
+    with_controller(|controller| {
+        if let Some(size) = controller.regions.release(start) {
+            controller.unmap_pages(start, size);
+        }
+    })
+}
+
+// Resolve a page fault at `address`. `protection_violation` is set if the
+// page was present and `write` if the access was a write, as reported in the
+// error code of the fault.
+//
+// Runs inside the page fault handler, so the controller is only tried: if it
+// is locked, the fault came from code holding it and waiting would deadlock.
+pub fn handle_page_fault(address: VirtualAddress, protection_violation: bool,
+                         write: bool) -> Result<(), PageFault> {
+    if !is_canonical(address) {
+        return Err(PageFault::NonCanonical);
+    }
+    let mut controller = MEMORY_CONTROLLER.try_lock().ok_or(PageFault::ControllerLocked)?;
+    controller.as_mut().expect("memory::init has not been called")
+        .handle_page_fault(address, protection_violation, write)
+}
+
+// The bottom and top of the stack whose guard page contains `address`. Used
+// after a double fault, which is what a stack overflow without a separate
+// fault stack turns into.
+pub fn guarded_stack(address: VirtualAddress) -> Option<(usize, usize)> {
+    if !is_canonical(address) {
+        return None;
+    }
+    let controller = MEMORY_CONTROLLER.try_lock()?;
+    controller.as_ref().and_then(|controller| controller.stack_allocator.guarded_stack(address))
+}
+
+fn is_canonical(address: VirtualAddress) -> bool {
+    address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000
+}
+
+// Translate `address` through the active page table. Returns None if it is
+// unmapped or not canonical.
+pub fn translate(address: VirtualAddress) -> Option<PhysicalAddress> {
+    if !is_canonical(address) {
+        return None;
     }
+    let mapper = unsafe { paging::Mapper::new() };
+    mapper.translate(address)
 }
 
 pub struct MemoryController {
     active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator,
+    frame_allocator: BitmapFrameAllocator,
     stack_allocator: stack_allocator::StackAllocator,
+    temporary_page: Option<paging::TemporaryPage>,
+    regions: page_fault::Regions,
 }
 
 impl MemoryController {
@@ -109,5 +279,183 @@ impl MemoryController {
         } = self;
         stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
     }
+
+    // Map [start, start + size) to newly allocated frames. Fails without
+    // mapping anything if a page of the range is mapped already.
+    pub fn map_pages(&mut self, start: VirtualAddress, size: usize,
+                     flags: EntryFlags) -> Result<(), &'static str> {
+        let start_page = paging::Page::containing_address(start);
+        let end_page = paging::Page::containing_address(start + size - 1);
+        for page in paging::Page::range_inclusive(start_page, end_page) {
+            if self.active_table.translate_page(page).is_some() {
+                return Err("Range is already mapped.");
+            }
+        }
+        for page in paging::Page::range_inclusive(start_page, end_page) {
+            match self.frame_allocator.allocate_frame() {
+                Some(frame) => self.active_table.map_to(page, frame, flags, &mut self.frame_allocator),
+                None => {
+                    if page != start_page {
+                        self.unmap_pages(start, page.start_address() - start);
+                    }
+                    return Err("Out of physical memory.");
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Unmap [start, start + size) and free the frames behind it. Pages that
+    // are not mapped are skipped.
+    pub fn unmap_pages(&mut self, start: VirtualAddress, size: usize) {
+        let start_page = paging::Page::containing_address(start);
+        let end_page = paging::Page::containing_address(start + size - 1);
+        for page in paging::Page::range_inclusive(start_page, end_page) {
+            if self.active_table.translate_page(page).is_some() {
+                self.active_table.unmap_and_free(page, &mut self.frame_allocator);
+            }
+        }
+    }
+
+    // A new page table that shares the P4 entries at `shared` with the active
+    // one and has nothing else mapped.
+    fn new_table<I>(&mut self, shared: I) -> Result<paging::InactivePageTable, &'static str>
+        where I: Iterator<Item = usize> {
+        let frame = self.frame_allocator.allocate_frame().ok_or("Out of physical memory.")?;
+        let mut table = paging::InactivePageTable::new(frame, &mut self.active_table,
+                                                       self.temporary_page.as_mut());
+        table.share(shared, &mut self.active_table, self.temporary_page.as_mut());
+        Ok(table)
+    }
+
+    // Run `f` on `table`, which may be the active table as well.
+    fn with_table<F, R>(&mut self, table: &mut paging::InactivePageTable, f: F) -> R
+        where F: FnOnce(&mut paging::Mapper, &mut BitmapFrameAllocator) -> R {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temporary_page,
+            ..
+        } = self;
+        if table.is_active() {
+            f(active_table, frame_allocator)
+        } else {
+            active_table.with(table, temporary_page.as_mut(), |mapper| f(mapper, frame_allocator))
+        }
+    }
+
+    // Map a zeroed frame at the page of `address` if it lies in a reserved
+    // region, copy a page on the first write if it is shared copy on write,
+    // otherwise tell why the fault is fatal.
+    pub fn handle_page_fault(&mut self, address: VirtualAddress, protection_violation: bool,
+                             write: bool) -> Result<(), PageFault> {
+        if let Some((bottom, top)) = self.stack_allocator.guarded_stack(address) {
+            return Err(PageFault::StackOverflow { bottom: bottom, top: top });
+        }
+        if protection_violation && write {
+            if let Some(result) = self.copy_on_write(address) {
+                return result;
+            }
+        }
+        if protection_violation {
+            return Err(PageFault::ProtectionViolation);
+        }
+        let flags = self.regions.lookup(address).ok_or(PageFault::NotReserved)?;
+
+        let page = paging::Page::containing_address(address);
+        let frame = self.frame_allocator.allocate_frame().ok_or(PageFault::OutOfMemory)?;
+        // zero the frame through a writable mapping, then apply the real flags
+        self.active_table.map_to(page, frame, WRITABLE, &mut self.frame_allocator);
+        unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE) };
+        if flags != WRITABLE {
+            self.active_table.set_flags(page, flags);
+        }
+        Ok(())
+    }
+
+    // Give the page at `address` a writable frame of its own. None if the page
+    // is not marked copy on write.
+    fn copy_on_write(&mut self, address: VirtualAddress) -> Option<Result<(), PageFault>> {
+        let page = paging::Page::containing_address(address);
+        let (frame, flags) = self.active_table.entry(page)?;
+        if !flags.contains(COPY_ON_WRITE) {
+            return None;
+        }
+        let flags = (flags - COPY_ON_WRITE) | WRITABLE;
+        if !self.frame_allocator.is_shared(&frame) {
+            // every other mapping is gone already
+            self.active_table.set_flags(page, flags);
+            return Some(Ok(()));
+        }
+
+        let copy = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return Some(Err(PageFault::OutOfMemory)),
+        };
+        let source = page.start_address() as *const u8;
+        if let Some(offset) = paging::physical_memory_offset() {
+            unsafe { ptr::copy_nonoverlapping(source, (copy.start_address() + offset) as *mut u8, PAGE_SIZE) };
+        } else {
+            let scratch = paging::Page::containing_address(SCRATCH_PAGE);
+            self.active_table.map_to(scratch, copy.clone(), WRITABLE, &mut self.frame_allocator);
+            unsafe { ptr::copy_nonoverlapping(source, SCRATCH_PAGE as *mut u8, PAGE_SIZE) };
+            self.active_table.unmap(scratch, &mut self.frame_allocator);
+        }
+        let shared = self.active_table.remap(page, copy, flags);
+        // only drops this mapping's reference
+        self.frame_allocator.deallocate_frame(shared);
+        Some(Ok(()))
+    }
+
+    // Allocate `count` physically contiguous frames aligned to `align` frames,
+    // e.g. for DMA buffers.
+    pub fn allocate_frames(&mut self, count: usize, align: usize) -> Option<Frame> {
+        self.frame_allocator.allocate_contiguous(count, align)
+    }
+
+    pub fn deallocate_frames(&mut self, frame: Frame, count: usize) {
+        self.frame_allocator.deallocate_contiguous(frame, count)
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.frame_allocator.free_frames()
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.frame_allocator.used_frames()
+    }
+
+    // Map the physical range [physical, physical + size) at `virtual_address`,
+    // with huge pages where alignment allows, e.g. for a framebuffer.
+    pub fn map_physical(&mut self, virtual_address: VirtualAddress, physical: PhysicalAddress,
+                        size: usize, flags: EntryFlags) {
+        let page = paging::Page::containing_address(virtual_address);
+        let frame = Frame::containing_address(physical);
+        self.active_table.map_range(page, frame, size, flags, &mut self.frame_allocator);
+    }
+
+    // Identity map the physical range [start, start + size), e.g. for ACPI
+    // tables or MMIO registers. Pages that are identity mapped already are
+    // left alone, nothing is mapped if a page maps to another frame.
+    pub fn identity_map(&mut self, start: PhysicalAddress, size: usize,
+                        flags: EntryFlags) -> Result<(), &'static str> {
+        let start_frame = Frame::containing_address(start);
+        let end_frame = Frame::containing_address(start + size - 1);
+        for frame in Frame::range_inclusive(start_frame.clone(), end_frame.clone()) {
+            let address = frame.start_address();
+            match self.active_table.translate(address) {
+                Some(physical) if physical != address => {
+                    return Err("Range is mapped to other memory.");
+                },
+                _ => {},
+            }
+        }
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            if self.active_table.translate(frame.start_address()).is_none() {
+                self.active_table.identity_map(frame, flags, &mut self.frame_allocator);
+            }
+        }
+        Ok(())
+    }
 }
 
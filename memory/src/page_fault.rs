@@ -0,0 +1,94 @@
+use core::fmt;
+
+use super::{VirtualAddress, EntryFlags, PAGE_SIZE};
+
+// Regions that can be reserved at the same time
+const MAX_REGIONS: usize = 32;
+
+// A virtual range that is backed by zeroed frames on first access.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: VirtualAddress,
+    end: VirtualAddress,   // exclusive
+    flags: EntryFlags,
+}
+
+// The reserved-but-unmapped regions. Lives in the memory controller, so
+// reserving a region does not allocate from the heap.
+pub struct Regions {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl Regions {
+    pub fn new() -> Regions {
+        Regions { regions: [None; MAX_REGIONS] }
+    }
+
+    pub fn reserve(&mut self, start: VirtualAddress, size: usize,
+                   flags: EntryFlags) -> Result<(), &'static str> {
+        if start % PAGE_SIZE != 0 || size == 0 {
+            return Err("Region must be page aligned and not empty.");
+        }
+        let end = start.checked_add(size).ok_or("Region wraps around.")?;
+        let overlaps = self.regions.iter()
+            .filter_map(|region| *region)
+            .any(|region| start < region.end && region.start < end);
+        if overlaps {
+            return Err("Region overlaps a reserved region.");
+        }
+        let slot = self.regions.iter_mut()
+            .find(|region| region.is_none())
+            .ok_or("Too many reserved regions.")?;
+        *slot = Some(Region { start: start, end: end, flags: flags });
+        Ok(())
+    }
+
+    // Forget the region starting at `start` and return its size.
+    pub fn release(&mut self, start: VirtualAddress) -> Option<usize> {
+        let slot = self.regions.iter_mut()
+            .find(|region| region.map_or(false, |region| region.start == start))?;
+        let size = slot.map(|region| region.end - region.start);
+        *slot = None;
+        size
+    }
+
+    // The flags to map `address` with, None if it is not reserved.
+    pub fn lookup(&self, address: VirtualAddress) -> Option<EntryFlags> {
+        self.regions.iter()
+            .filter_map(|region| *region)
+            .find(|region| region.start <= address && address < region.end)
+            .map(|region| region.flags)
+    }
+}
+
+// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy)]
+pub enum PageFault {
+    // the address is not canonical
+    NonCanonical,
+    // the address is neither mapped nor in a reserved region
+    NotReserved,
+    // the guard page below the stack [bottom, top) was hit
+    StackOverflow { bottom: usize, top: usize },
+    // the page is present, but the access is not allowed
+    ProtectionViolation,
+    // no frame was left to back the page
+    OutOfMemory,
+    // the fault happened while the memory controller was locked
+    ControllerLocked,
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PageFault::NonCanonical => write!(f, "non-canonical address"),
+            PageFault::NotReserved => write!(f, "address is not mapped or reserved"),
+            PageFault::StackOverflow { bottom, top } => {
+                write!(f, "stack overflow, hit the guard page of the stack {:#x}..{:#x}", bottom, top)
+            },
+            PageFault::ProtectionViolation => write!(f, "access violates the page protection"),
+            PageFault::OutOfMemory => write!(f, "out of physical memory"),
+            PageFault::ControllerLocked => write!(f, "fault inside the memory controller"),
+        }
+    }
+}
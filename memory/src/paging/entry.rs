@@ -43,6 +43,7 @@ bitflags! {
         const DIRTY           = 1 << 6;
         const HUGE_PAGE       = 1 << 7;
         const GLOBAL          = 1 << 8;
+        const COPY_ON_WRITE   = 1 << 9;     // available to the OS, see `AddressSpace::clone_cow`
         const NO_EXECUTE      = 1 << 63;
     }
 }
@@ -1,24 +1,51 @@
-use super::{VirtualAddress, PhysicalAddress, Page};
+use super::{VirtualAddress, PhysicalAddress, Page, PageSize, ENTRY_COUNT};
 use super::entry::*;
 use super::table::{self, Table, Level4};
 use super::{PAGE_SIZE, Frame, FrameAllocator};
 use core::ptr::Unique;
 
 pub struct Mapper {
-    p4: Unique<Table<Level4>>,
+    p4: Option<Unique<Table<Level4>>>,    // None for the active table
+}
+
+// The active P4 table, through the mapping of all physical memory if there is
+// one, else through the recursive entry.
+fn active_p4() -> *mut Table<Level4> {
+    use x86_64::registers::control_regs;
+
+    match super::physical_memory_offset() {
+        Some(offset) => {
+            let frame = Frame::containing_address(control_regs::cr3().0 as usize);
+            (frame.start_address() + offset) as *mut _
+        },
+        None => table::P4,
+    }
 }
 
 impl Mapper {
+    // A mapper for whatever table CR3 points to.
     pub unsafe fn new() -> Mapper {
-        Mapper { p4: Unique::new_unchecked(table::P4) }
+        Mapper { p4: None }
+    }
+
+    // A mapper for the P4 table at `p4`, which must stay accessible while the
+    // mapper is used.
+    pub unsafe fn with_p4(p4: *mut Table<Level4>) -> Mapper {
+        Mapper { p4: Some(Unique::new_unchecked(p4)) }
     }
 
     pub fn p4(&self) -> &Table<Level4> {
-        unsafe { self.p4.as_ref() }
+        match self.p4 {
+            Some(ref p4) => unsafe { p4.as_ref() },
+            None => unsafe { &*active_p4() },
+        }
     }
 
     pub fn p4_mut(&mut self) -> &mut Table<Level4> {
-        unsafe { self.p4.as_mut() }
+        match self.p4 {
+            Some(ref mut p4) => unsafe { p4.as_mut() },
+            None => unsafe { &mut *active_p4() },
+        }
     }
 
     pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
@@ -28,11 +55,38 @@ impl Mapper {
     }
 
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
+        let p3 = self.p4().next_table(page.p4_index())?;
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(HUGE_PAGE) {
+            return p3_entry.pointed_frame().map(|start| Frame {
+                number: start.number + page.p2_index() * ENTRY_COUNT + page.p1_index(),
+            });
+        }
+
+        let p2 = p3.next_table(page.p3_index())?;
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(HUGE_PAGE) {
+            return p2_entry.pointed_frame().map(|start| Frame {
+                number: start.number + page.p1_index(),
+            });
+        }
 
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| p1[page.p1_index()].pointed_frame())
+        let p1 = p2.next_table(page.p2_index())?;
+        p1[page.p1_index()].pointed_frame()
+    }
+
+    // The size of the page `page` is mapped with, None if it is not mapped.
+    pub fn page_size(&self, page: Page) -> Option<PageSize> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+        if p3[page.p3_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(PageSize::Size1GiB);
+        }
+        let p2 = p3.next_table(page.p3_index())?;
+        if p2[page.p2_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(PageSize::Size2MiB);
+        }
+        let p1 = p2.next_table(page.p2_index())?;
+        p1[page.p1_index()].pointed_frame().map(|_| PageSize::Size4KiB)
     }
 
     pub fn map_to<A>(&mut self, page: Page, frame: Frame,
@@ -45,6 +99,37 @@ impl Mapper {
         p1[page.p1_index()].set(frame, flags | PRESENT);
     }
 
+    // The frame and flags of the 4 KiB page `page`.
+    pub fn entry(&self, page: Page) -> Option<(Frame, EntryFlags)> {
+        let p1 = self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))?;
+        let entry = &p1[page.p1_index()];
+        entry.pointed_frame().map(|frame| (frame, entry.flags()))
+    }
+
+    // Point the mapped 4 KiB page `page` to `frame` with `flags`. Returns the
+    // frame it was mapped to before.
+    pub fn remap(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> Frame {
+        use x86_64::VirtualAddress;
+        use x86_64::instructions::tlb;
+
+        let p1 = self.p4_mut().next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .expect("page is not mapped with 4 KiB pages");
+        let old_frame = p1[page.p1_index()].pointed_frame().expect("page is not mapped");
+        p1[page.p1_index()].set(frame, flags | PRESENT);
+        tlb::flush(VirtualAddress(page.start_address()));
+        old_frame
+    }
+
+    // Replace the flags of the 4 KiB page `page`, keeping its frame.
+    pub fn set_flags(&mut self, page: Page, flags: EntryFlags) {
+        let (frame, _) = self.entry(page).expect("page is not mapped");
+        self.remap(page, frame, flags);
+    }
+
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags,
                   allocator: &mut A) where A: FrameAllocator {
         let frame = allocator.allocate_frame().expect("out of memory");
@@ -57,21 +142,128 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator)
     }
 
-    pub fn unmap<A>(&mut self, page: Page, _allocator: &mut A) where A: FrameAllocator {
+    // Map the 2 MiB or 1 GiB page starting at `page` to the frames starting at
+    // `frame`. Both have to be aligned to `size`.
+    pub fn map_to_huge<A>(&mut self, page: Page, frame: Frame, size: PageSize,
+                          flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
+        assert!(page.number % size.frames() == 0 && frame.number % size.frames() == 0,
+                "huge pages must be aligned to their size");
+        let flags = flags | PRESENT | HUGE_PAGE;
+
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+        match size {
+            PageSize::Size1GiB => {
+                assert!(super::supports_1gib_pages(), "1 GiB pages are not supported");
+                assert!(p3[page.p3_index()].is_unused());
+                p3[page.p3_index()].set(frame, flags);
+            }
+            PageSize::Size2MiB => {
+                let p2 = p3.next_table_create(page.p3_index(), allocator);
+                assert!(p2[page.p2_index()].is_unused());
+                p2[page.p2_index()].set(frame, flags);
+            }
+            PageSize::Size4KiB => panic!("use map_to for 4 KiB pages"),
+        }
+    }
+
+    // Map `size` bytes at `page` to the frames starting at `frame`, using the
+    // largest pages alignment allows.
+    pub fn map_range<A>(&mut self, page: Page, frame: Frame, size: usize,
+                        flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
+        let count = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let huge_1gib = super::supports_1gib_pages();
+        let mut done = 0;
+        while done < count {
+            let page = page + done;
+            let frame_number = frame.number + done;
+            let fits = |size: PageSize| {
+                page.number % size.frames() == 0 && frame_number % size.frames() == 0
+                    && count - done >= size.frames()
+            };
+            let size = if huge_1gib && fits(PageSize::Size1GiB) {
+                PageSize::Size1GiB
+            } else if fits(PageSize::Size2MiB) {
+                PageSize::Size2MiB
+            } else {
+                PageSize::Size4KiB
+            };
+            let frame = Frame { number: frame_number };
+            if size == PageSize::Size4KiB {
+                self.map_to(page, frame, flags, allocator);
+            } else {
+                self.map_to_huge(page, frame, size, flags, allocator);
+            }
+            done += size.frames();
+        }
+    }
+
+    // Unmap the page starting at `page`, whatever its size, and return the
+    // first frame it was mapped to. P1 and P2 tables left empty are given
+    // back to `allocator`.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame where A: FrameAllocator {
         use x86_64::VirtualAddress;
         use x86_64::instructions::tlb;
 
-        assert!(self.translate(page.start_address()).is_some());
+        let size = self.page_size(page).expect("page is not mapped");
+        assert!(page.number % size.frames() == 0, "{:?} is inside a huge page", page);
 
-        let p1 = self.p4_mut()
+        let frame = {
+            let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+            let entry = match size {
+                PageSize::Size1GiB => &mut p3[page.p3_index()],
+                _ => {
+                    let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+                    match size {
+                        PageSize::Size2MiB => &mut p2[page.p2_index()],
+                        _ => &mut p2.next_table_mut(page.p2_index()).unwrap()[page.p1_index()],
+                    }
+                }
+            };
+            let frame = entry.pointed_frame().unwrap();
+            entry.set_unused();
+            frame
+        };
+        // one invalidation drops the whole TLB entry, even for a huge page
+        tlb::flush(VirtualAddress(page.start_address()));
+
+        // free the P1 and P2 table bottom up, as long as they are empty. P3
+        // tables stay: P4 entries are copied into other address spaces, which
+        // would keep pointing to a freed table.
+        let p1_freed = size != PageSize::Size4KiB || self.p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        //let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
-        tlb::flush(VirtualAddress(page.start_address()));
-        // TODO free p(1,2,3) table if empty
-        // allocator.deallocate_frame(frame);
+            .map_or(false, |p2| p2.free_next_table_if_empty(page.p2_index(), allocator));
+        if p1_freed && size != PageSize::Size1GiB {
+            if let Some(p3) = self.p4_mut().next_table_mut(page.p4_index()) {
+                p3.free_next_table_if_empty(page.p3_index(), allocator);
+            }
+        }
+
+        frame
+    }
+
+    // Free the page tables below the P4 entry `index` once nothing is mapped
+    // there. Tables that still map something are kept.
+    pub fn free_tables<A>(&mut self, index: usize, allocator: &mut A) where A: FrameAllocator {
+        if let Some(p3) = self.p4_mut().next_table_mut(index) {
+            for p3_index in 0..ENTRY_COUNT {
+                if let Some(p2) = p3.next_table_mut(p3_index) {
+                    for p2_index in 0..ENTRY_COUNT {
+                        p2.free_next_table_if_empty(p2_index, allocator);
+                    }
+                }
+                p3.free_next_table_if_empty(p3_index, allocator);
+            }
+        }
+        self.p4_mut().free_next_table_if_empty(index, allocator);
+    }
+
+    // Unmap the page starting at `page` and free the frames it was mapped to.
+    pub fn unmap_and_free<A>(&mut self, page: Page, allocator: &mut A) where A: FrameAllocator {
+        let size = self.page_size(page).expect("page is not mapped");
+        let frame = self.unmap(page, allocator);
+        for number in frame.number..frame.number + size.frames() {
+            allocator.deallocate_frame(Frame { number: number });
+        }
     }
 }
@@ -1,8 +1,10 @@
 pub use self::entry::*;
 use super::{PAGE_SIZE, Frame, FrameAllocator};
-use self::temporary_page::TemporaryPage;
+pub use self::temporary_page::TemporaryPage;
 pub use self::mapper::Mapper;
+use self::table::{Table, Level1};
 use core::ops::{Add, Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
 use multiboot2::BootInformation;
 
 mod entry;
@@ -12,6 +14,51 @@ mod mapper;
 
 const ENTRY_COUNT: usize = 512;
 
+// With the `phys_offset` feature, all physical memory is mapped here, at the
+// start of the higher half. Once it is, page tables are reached through this
+// mapping instead of the recursive P4 entry, so tables other than the active
+// one can be edited without temporary pages and TLB flushes.
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff_8000_0000_0000;
+
+static PHYSICAL_MEMORY_MAPPED: AtomicBool = AtomicBool::new(false);
+
+// The address physical memory is mapped at, None without the `phys_offset`
+// feature or until `map_physical_memory` is done.
+pub fn physical_memory_offset() -> Option<usize> {
+    if cfg!(feature = "phys_offset") && PHYSICAL_MEMORY_MAPPED.load(Ordering::Relaxed) {
+        Some(PHYSICAL_MEMORY_OFFSET)
+    } else {
+        None
+    }
+}
+
+// Map the physical memory [0, end) at `PHYSICAL_MEMORY_OFFSET`, with the
+// largest pages possible, and access page tables through it from now on.
+pub fn map_physical_memory<A>(active_table: &mut ActivePageTable, end: PhysicalAddress,
+                              allocator: &mut A) where A: FrameAllocator {
+    let page = Page::containing_address(PHYSICAL_MEMORY_OFFSET);
+    active_table.map_range(page, Frame::containing_address(0), end, WRITABLE | NO_EXECUTE, allocator);
+    PHYSICAL_MEMORY_MAPPED.store(true, Ordering::Relaxed);
+}
+
+// Run `f` on the page table in `frame`, through the mapping of all physical
+// memory if there is one, else through `temporary_page`.
+fn with_table_frame<F, R>(frame: &Frame, active_table: &mut ActivePageTable,
+                          temporary_page: Option<&mut TemporaryPage>, f: F) -> R
+    where F: FnOnce(&mut Table<Level1>, &mut ActivePageTable) -> R {
+    if let Some(offset) = physical_memory_offset() {
+        let table = unsafe { &mut *((frame.start_address() + offset) as *mut Table<Level1>) };
+        return f(table, active_table);
+    }
+    let temporary_page = temporary_page.expect("no temporary page to reach the table");
+    let result = {
+        let table = temporary_page.map_table_frame(frame.clone(), active_table);
+        f(table, active_table)
+    };
+    temporary_page.unmap(active_table);
+    result
+}
+
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
@@ -83,6 +130,44 @@ impl Iterator for PageIter {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,    // a P2 entry with HUGE_PAGE set
+    Size1GiB,    // a P3 entry with HUGE_PAGE set, see `supports_1gib_pages`
+}
+
+impl PageSize {
+    // How many 4 KiB pages or frames the page spans.
+    pub fn frames(&self) -> usize {
+        match *self {
+            PageSize::Size4KiB => 1,
+            PageSize::Size2MiB => ENTRY_COUNT,
+            PageSize::Size1GiB => ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.frames() * PAGE_SIZE
+    }
+}
+
+// 1 GiB pages are optional, CPUID 0x80000001 reports them in EDX bit 26.
+pub fn supports_1gib_pages() -> bool {
+    let max_leaf: u32;
+    let features: u32;
+    let _eax: u32;
+    unsafe {
+        asm!("cpuid" : "={eax}"(max_leaf) : "{eax}"(0x8000_0000u32) : "rbx", "rcx", "rdx");
+        if max_leaf < 0x8000_0001 {
+            return false;
+        }
+        // eax is an input, so it is written back as an output instead of clobbered
+        asm!("cpuid" : "={eax}"(_eax), "={edx}"(features) : "{eax}"(0x8000_0001u32) : "rbx", "rcx");
+    }
+    features & (1 << 26) != 0
+}
+
 pub struct ActivePageTable {
     mapper: Mapper,
 }
@@ -106,18 +191,27 @@ impl ActivePageTable {
         ActivePageTable { mapper: Mapper::new() }
     }
 
-    pub fn with<F>(
+    pub fn with<F, R>(
         &mut self,
         table: &mut InactivePageTable,
-        temporary_page: &mut temporary_page::TemporaryPage, // new
+        temporary_page: Option<&mut TemporaryPage>,
         f: F,
-    ) where
-        F: FnOnce(&mut Mapper),
+    ) -> R where
+        F: FnOnce(&mut Mapper) -> R,
     {
         use x86_64::registers::control_regs;
         use x86_64::instructions::tlb;
 
-        {
+        // the tables are reachable directly, the recursive mapping stays
+        if let Some(offset) = physical_memory_offset() {
+            let mut mapper = unsafe {
+                Mapper::with_p4((table.p4_frame.start_address() + offset) as *mut _)
+            };
+            return f(&mut mapper);
+        }
+
+        let temporary_page = temporary_page.expect("no temporary page to reach the table");
+        let result = {
             let backup = Frame::containing_address(control_regs::cr3().0 as usize);
 
             // map temporary_page to current p4 table
@@ -128,17 +222,25 @@ impl ActivePageTable {
             tlb::flush_all();
 
             // execute f in the new context
-            f(self);
+            let result = f(self);
 
             // restore recursive mapping to original p4 table
             p4_table[511].set(backup, PRESENT | WRITABLE);
             tlb::flush_all();
-        }
+            result
+        };
 
         temporary_page.unmap(self);
+        result
     }
 
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
+        self.switch_to(&new_table)
+    }
+
+    // Load `table` into CR3, it stays owned by the caller. Returns the table
+    // that was active.
+    pub fn switch_to(&mut self, new_table: &InactivePageTable) -> InactivePageTable {
         use x86_64::PhysicalAddress;
         use x86_64::registers::control_regs;
 
@@ -160,17 +262,45 @@ impl InactivePageTable {
     pub fn new(
         frame: Frame,
         active_table: &mut ActivePageTable,
-        temporary_page: &mut TemporaryPage,
+        temporary_page: Option<&mut TemporaryPage>,
     ) -> InactivePageTable {
-        {
-            let table = temporary_page.map_table_frame(frame.clone(), active_table);
+        with_table_frame(&frame, active_table, temporary_page, |table, _| {
             table.zero();
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
-        }
-        temporary_page.unmap(active_table);
+            // tables are reached through the physical memory mapping once
+            // there is one, only the boot table keeps its recursive entry
+            if physical_memory_offset().is_none() {
+                table[511].set(frame.clone(), PRESENT | WRITABLE);
+            }
+        });
 
         InactivePageTable { p4_frame: frame }
     }
+
+    pub fn p4_frame(&self) -> &Frame {
+        &self.p4_frame
+    }
+
+    // Whether CR3 points to this table.
+    pub fn is_active(&self) -> bool {
+        use x86_64::registers::control_regs;
+
+        Frame::containing_address(control_regs::cr3().0 as usize) == self.p4_frame
+    }
+
+    // Copy the P4 entries at `indices` from the active table, so that both
+    // tables share everything mapped below them.
+    pub fn share<I>(&mut self, indices: I, active_table: &mut ActivePageTable,
+                    temporary_page: Option<&mut TemporaryPage>) where I: Iterator<Item = usize> {
+        with_table_frame(&self.p4_frame, active_table, temporary_page, |table, active_table| {
+            for index in indices {
+                let entry = &active_table.p4()[index];
+                match entry.pointed_frame() {
+                    Some(frame) => table[index].set(frame, entry.flags()),
+                    None => table[index].set_unused(),
+                }
+            }
+        });
+    }
 }
 
 pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
@@ -182,10 +312,10 @@ where
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         let frame = allocator.allocate_frame().expect("no more frames");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+        InactivePageTable::new(frame, &mut active_table, Some(&mut temporary_page))
     };
 
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+    active_table.with(&mut new_table, Some(&mut temporary_page), |mapper| {
         let elf_sections_tag = boot_info
             .elf_sections_tag()
             .expect("Memory map tag required");
@@ -231,6 +361,7 @@ where
     let old_table = active_table.switch(new_table);
     kprintln!("NEW TABLE!!!");
 
+    // the old P4 frame stays allocated, its page is the guard page now
     let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
     active_table.unmap(old_p4_page, allocator);
     kprintln!("guard page at {:#x}", old_p4_page.start_address());
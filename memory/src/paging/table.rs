@@ -17,6 +17,10 @@ impl<L> Table<L> where L: TableLevel {
             entry.set_unused();
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 /*
@@ -32,6 +36,9 @@ impl<L> Table<L> where L: HierarchicalLevel {
     fn next_table_address(&self, index: usize) -> Option<usize> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
+            if let Some(offset) = super::physical_memory_offset() {
+                return self[index].pointed_frame().map(|frame| frame.start_address() + offset);
+            }
             let table_address = self as *const _ as usize;
             Some(make_address_canonical((table_address << 9) | (index << 12)))
         } else {
@@ -62,6 +69,29 @@ impl<L> Table<L> where L: HierarchicalLevel {
         }
         self.next_table_mut(index).unwrap()
     }
+
+    // Free the table at `index` if none of its entries are used any more.
+    // Returns true if it was freed.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A)
+        -> bool where A: FrameAllocator {
+        use x86_64::VirtualAddress;
+        use x86_64::instructions::tlb;
+
+        match self.next_table(index) {
+            Some(table) if table.is_empty() => {},
+            _ => return false,
+        }
+        let table_address = self.next_table_address(index).unwrap();
+        let frame = self.entries[index].pointed_frame().unwrap();
+        self.entries[index].set_unused();
+        // drop the stale recursive mapping of the table, the mapping of all
+        // physical memory stays valid
+        if super::physical_memory_offset().is_none() {
+            tlb::flush(VirtualAddress(table_address));
+        }
+        allocator.deallocate_frame(frame);
+        true
+    }
 }
 
 impl<L> Index<usize> for Table<L> where L: TableLevel {
@@ -39,9 +39,10 @@ impl TemporaryPage {
         unsafe { &mut *(self.map(frame, active_table) as *mut Table<Level1>) }
     }
 
-    // Unmaps the temporary page in the active table.
+    // Unmaps the temporary page in the active table. The frame it showed is
+    // not ours to free, but the page tables go back to the tiny allocator.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        active_table.unmap(self.page, &mut self.allocator);
     }
 }
 
@@ -0,0 +1,190 @@
+use alloc::heap::{Alloc, AllocErr, Layout};
+
+use core::ptr;
+use sync::IrqMutex;
+
+use super::{PAGE_SIZE, WRITABLE};
+
+// Object sizes with their own cache. Larger requests get whole pages.
+const CLASS_COUNT: usize = 7;
+const SIZE_CLASSES: [usize; CLASS_COUNT] = [32, 64, 128, 256, 512, 1024, 2048];
+
+// Virtual ranges freed by large allocations, kept for reuse.
+const MAX_FREE_RANGES: usize = 64;
+
+struct SlabState {
+    // Heads of the free object lists, one per size class, 0 if empty. Every
+    // free object stores the address of the next one in its first word.
+    free_lists: [usize; CLASS_COUNT],
+    // Start and page count of unmapped ranges below `next_page`
+    free_ranges: [Option<(usize, usize)>; MAX_FREE_RANGES],
+    // Pages mapped at boot that were never handed out
+    boot_pages: Option<(usize, usize)>,
+    // Pages from here to `end` were never handed out
+    next_page: usize,
+    end: usize,
+}
+
+// A size class allocator. Small objects come from caches of equally sized
+// objects carved out of single pages, which makes allocating and freeing
+// constant time and keeps objects of a size together. Requests beyond the
+// largest class are served with freshly mapped pages and unmapped again
+// when freed.
+//
+// Slab pages are never given back: a cache keeps the memory of its peak use.
+pub struct SlabAllocator {
+    state: IrqMutex<SlabState>,
+}
+
+impl SlabAllocator {
+    pub const fn empty() -> SlabAllocator {
+        SlabAllocator {
+            state: IrqMutex::new(SlabState {
+                free_lists: [0; CLASS_COUNT],
+                free_ranges: [None; MAX_FREE_RANGES],
+                boot_pages: None,
+                next_page: 0,
+                end: 0,
+            }),
+        }
+    }
+
+    // Use the virtual range [start, start + max_size) for the allocator's
+    // pages. The first `size` bytes are mapped already and used first.
+    pub unsafe fn init(&self, start: usize, size: usize, max_size: usize) {
+        assert!(start % PAGE_SIZE == 0 && size <= max_size);
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut state = self.state.lock();
+        state.boot_pages = if pages > 0 { Some((start, pages)) } else { None };
+        state.next_page = start + pages * PAGE_SIZE;
+        state.end = start + max_size;
+    }
+}
+
+// The cache for `layout`, None if it needs whole pages.
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|class| *class >= size)
+}
+
+fn page_count(layout: &Layout) -> usize {
+    (layout.size() + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+impl SlabState {
+    // Find `count` contiguous virtual pages and map them.
+    fn alloc_pages(&mut self, count: usize) -> Option<usize> {
+        if let Some((start, pages)) = self.boot_pages {
+            if pages >= count {
+                self.boot_pages = if pages == count { None } else { Some((start + count * PAGE_SIZE, pages - count)) };
+                return Some(start);
+            }
+        }
+        let reused = self.free_ranges.iter_mut()
+            .find(|range| range.map_or(false, |(_, pages)| pages >= count))
+            .map(|range| {
+                let (start, pages) = range.unwrap();
+                *range = if pages == count { None } else { Some((start + count * PAGE_SIZE, pages - count)) };
+                start
+            });
+        let start = match reused {
+            Some(start) => start,
+            None if self.next_page + count * PAGE_SIZE <= self.end => {
+                let start = self.next_page;
+                self.next_page += count * PAGE_SIZE;
+                start
+            },
+            None => return None,
+        };
+
+        if super::map_pages(start, count * PAGE_SIZE, WRITABLE).is_err() {
+            self.free_pages(start, count);
+            return None;
+        }
+        Some(start)
+    }
+
+    // Unmap `count` pages at `start` and keep the range for reuse, merged
+    // with the free ranges on both sides.
+    fn free_pages(&mut self, start: usize, count: usize) {
+        super::unmap_pages(start, count * PAGE_SIZE);
+
+        let mut start = start;
+        let mut end = start + count * PAGE_SIZE;
+        for range in self.free_ranges.iter_mut() {
+            if let Some((range_start, pages)) = *range {
+                let range_end = range_start + pages * PAGE_SIZE;
+                if range_end == start {
+                    start = range_start;
+                    *range = None;
+                } else if range_start == end {
+                    end = range_end;
+                    *range = None;
+                }
+            }
+        }
+        if end == self.next_page {
+            self.next_page = start;
+            return;
+        }
+        let pages = (end - start) / PAGE_SIZE;
+        match self.free_ranges.iter_mut().find(|range| range.is_none()) {
+            Some(range) => *range = Some((start, pages)),
+            // the frames are freed anyway, only the virtual range is lost
+            None => kprintln!("slab: no slot left for the free range {:#x}..{:#x}, dropping it", start, end),
+        }
+    }
+
+    // Carve a new page into objects for size class `class`.
+    fn refill(&mut self, class: usize) -> bool {
+        let page = match self.alloc_pages(1) {
+            Some(page) => page,
+            None => return false,
+        };
+        let size = SIZE_CLASSES[class];
+        for index in (0..PAGE_SIZE / size).rev() {
+            let object = page + index * size;
+            unsafe { ptr::write(object as *mut usize, self.free_lists[class]) };
+            self.free_lists[class] = object;
+        }
+        true
+    }
+}
+
+unsafe impl<'a> Alloc for &'a SlabAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let mut state = self.state.lock();
+        match size_class(&layout) {
+            Some(class) => {
+                if state.free_lists[class] == 0 && !state.refill(class) {
+                    return Err(AllocErr::Exhausted { request: layout });
+                }
+                let object = state.free_lists[class];
+                state.free_lists[class] = ptr::read(object as *const usize);
+                Ok(object as *mut u8)
+            },
+            None if layout.align() > PAGE_SIZE => {
+                Err(AllocErr::Unsupported { details: "alignment above the page size" })
+            },
+            None => match state.alloc_pages(page_count(&layout)) {
+                Some(start) => Ok(start as *mut u8),
+                None => Err(AllocErr::Exhausted { request: layout }),
+            },
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut state = self.state.lock();
+        match size_class(&layout) {
+            Some(class) => {
+                ptr::write(ptr as *mut usize, state.free_lists[class]);
+                state.free_lists[class] = ptr as usize;
+            },
+            None => state.free_pages(ptr as usize, page_count(&layout)),
+        }
+    }
+
+    fn oom(&mut self, _: AllocErr) -> ! {
+        panic!("Out of memory");
+    }
+}
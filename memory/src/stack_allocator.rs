@@ -1,13 +1,30 @@
 use super::paging::{self, Page, PageIter, ActivePageTable};
 use super::{PAGE_SIZE, FrameAllocator};
 
+// Stacks whose guard page is remembered for page fault diagnostics
+const MAX_STACKS: usize = 32;
+
 pub struct StackAllocator {
     range: PageIter,
+    // guard page, bottom and top of the allocated stacks
+    stacks: [Option<(Page, usize, usize)>; MAX_STACKS],
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            stacks: [None; MAX_STACKS],
+        }
+    }
+
+    // The bottom and top of the stack whose guard page contains `address`.
+    pub fn guarded_stack(&self, address: usize) -> Option<(usize, usize)> {
+        let page = Page::containing_address(address);
+        self.stacks.iter()
+            .filter_map(|stack| *stack)
+            .find(|&(guard, _, _)| guard == page)
+            .map(|(_, bottom, top)| (bottom, top))
     }
 }
 
@@ -34,7 +51,7 @@ impl StackAllocator {
         };
 
         match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
+            (Some(guard), Some(start), Some(end)) => {
                 // success! write back updated range
                 self.range = range;
 
@@ -45,6 +62,9 @@ impl StackAllocator {
 
                 // create a new stack
                 let top_of_stack = end.start_address() + PAGE_SIZE;
+                if let Some(slot) = self.stacks.iter_mut().find(|stack| stack.is_none()) {
+                    *slot = Some((guard, start.start_address(), top_of_stack));
+                }
                 Some(Stack::new(top_of_stack, start.start_address()))
             }
             _ => None, /* not enough pages */
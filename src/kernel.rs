@@ -8,6 +8,7 @@
 extern crate vga;
 extern crate interrupt;
 extern crate device;
+extern crate sync;
 extern crate memory;
 extern crate filesystem;
 
@@ -15,24 +16,38 @@ extern crate filesystem;
 extern crate alloc; /* format */
 extern crate rlibc;
 extern crate multiboot2;
-extern crate linked_list_allocator;
 extern crate x86_64;
 
-use device::pic;
-use linked_list_allocator::LockedHeap;
+use device::{acpi, apic, ata, keyboard, pic, pit, rtc, serial};
+#[cfg(not(feature = "slab"))]
+use memory::heap_allocator::GrowableHeap;
+#[cfg(feature = "slab")]
+use memory::slab_allocator::SlabAllocator;
 
-const HEAP_START: usize = 0o_000_001_000_000_0000;
-const HEAP_SIZE:  usize = 100 * 1024; // 100 KiB
+const HEAP_START: usize    = 0o_000_001_000_000_0000;
+const HEAP_SIZE:  usize    = 100 * 1024;         // 100 KiB, mapped at boot
+const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;   // 64 MiB, grown into on demand
 
+#[cfg(not(feature = "slab"))]
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: GrowableHeap = GrowableHeap::empty();
+
+#[cfg(feature = "slab")]
+#[global_allocator]
+static HEAP_ALLOCATOR: SlabAllocator = SlabAllocator::empty();
 
 #[no_mangle]
 pub extern fn kmain(multiboot_info_addr: usize) -> ! {
     vga::clear_screen();
+    serial::init(serial::DEFAULT_BAUD);
+    vga::set_sink(Some(serial::kprint));
     kprintln!("Booting ...");
+    kprintln!("SERIAL INIT     {:>64}", "[ok]");
     pic::remap();                   kprintln!("PIC INIT        {:>64}", "[ok]");
+    pit::init(pit::DEFAULT_FREQUENCY); kprintln!("PIT INIT        {:>64}", "[ok]");
     interrupt::init();              kprintln!("INTERRUPT INIT  {:>64}", "[ok]");
+    interrupt::fpu::init();         kprintln!("FPU INIT        {:>64}", "[ok]");
+    register_irq_handlers();
     kprintln!(r"
 | | ___   _ _ __ _   _ _ __ ___ (_)
 | |/ | | | | '__| | | | '_ ` _ \| |
@@ -46,15 +61,98 @@ pub extern fn kmain(multiboot_info_addr: usize) -> ! {
     enable_write_protect_bit();
 
     let boot_info = unsafe{ multiboot2::load(multiboot_info_addr) };
-    memory::init(boot_info, HEAP_START, HEAP_SIZE);
-    unsafe { HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE); }
+    memory::init(boot_info, HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE);
+    init_heap();
+
+    match acpi::init() {
+        Some(madt) => {
+            apic::init(&madt);
+            kprintln!("APIC INIT       {:>64}", "[ok]");
+        }
+        None => kprintln!("APIC INIT       {:>64}", "[no MADT, keep 8259]"),
+    }
+
+    let double_fault_stack = memory::alloc_stack(2)
+        .expect("could not allocate double fault stack");
+    interrupt::gdt::init(&[double_fault_stack.top()]);
+    kprintln!("GDT INIT        {:>64}", "[ok]");
+
+    if cfg!(feature = "gdb") {
+        interrupt::gdbstub::enable();
+        kprintln!("Waiting for GDB on COM2 ...");
+        interrupt::debugger::breakpoint();
+    }
+
     for _ in 0..10000 {
         format!("Some String");
     }
 
     filesystem::test_read();
+    test_copy_on_write();
     kprint!("$ ");
-    loop {}
+    idle();
+}
+
+// Clone an address space and check that a write after the clone gets the
+// writer a copy of its own and leaves the clone alone.
+fn test_copy_on_write() {
+    use core::ptr;
+    use memory::{AddressSpace, Backing, USER_START};
+
+    let value = USER_START as *mut u64;
+    let mut parent = AddressSpace::new().expect("could not create an address space");
+    parent.map(USER_START, memory::PAGE_SIZE, memory::WRITABLE, Backing::Anonymous)
+        .expect("could not map a page");
+    let kernel_table = parent.activate();
+    unsafe { ptr::write_volatile(value, 1) };
+
+    let mut child = parent.clone_cow().expect("could not clone the address space");
+    assert_eq!(parent.translate(USER_START), child.translate(USER_START));
+    unsafe { ptr::write_volatile(value, 2) };    // faults and copies the page
+    assert!(parent.translate(USER_START) != child.translate(USER_START));
+
+    memory::switch_table(child.table());
+    let child_value = unsafe { ptr::read_volatile(value) };
+    memory::switch_table(&kernel_table);
+    assert_eq!(child_value, 1);
+    kprintln!("COW TEST        {:>64}", "[ok]");
+}
+
+// Run deferred work not picked up by an IRQ and sleep until the next interrupt.
+fn idle() -> ! {
+    loop {
+        sync::run_pending();
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
+
+#[cfg(not(feature = "slab"))]
+fn init_heap() {
+    unsafe { HEAP_ALLOCATOR.init(HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE, grow_heap) };
+}
+
+#[cfg(feature = "slab")]
+fn init_heap() {
+    unsafe { HEAP_ALLOCATOR.init(HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE) };
+}
+
+// Called by the heap when it runs out of space. The heap lock is held, so
+// nothing in here may allocate.
+#[cfg(not(feature = "slab"))]
+fn grow_heap(start: usize, size: usize) -> bool {
+    memory::map_pages(start, size, memory::WRITABLE).is_ok()
+}
+
+fn register_irq_handlers() {
+    interrupt::register_irq(0, pit::tick).unwrap();
+    interrupt::register_irq(1, keyboard::handle_interrupt).unwrap();
+    // with the gdb feature COM2 belongs to the GDB stub
+    if !cfg!(feature = "gdb") {
+        interrupt::register_irq(3, serial::handle_com2_interrupt).unwrap();
+    }
+    interrupt::register_irq(4, serial::handle_com1_interrupt).unwrap();
+    interrupt::register_irq(8, rtc::handle_interrupt).unwrap();
+    interrupt::register_irq(14, ata::handle_interrupt).unwrap();
 }
 
 fn enable_nxe_bit() {
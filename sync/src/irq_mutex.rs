@@ -0,0 +1,83 @@
+// A spinlock that keeps interrupts disabled while it is held.
+//
+// State shared with interrupt handlers must be protected by this lock. With a
+// plain `spin::Mutex`, an interrupt arriving while the lock is held would make
+// its handler spin forever on the same CPU. Dropping the guard restores the
+// interrupt flag as it was before `lock`, so guards nest and the lock can be
+// taken inside handlers as well.
+
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe { asm!("pushfq; popq $0" : "=r"(rflags) :: "memory" : "volatile") };
+    rflags & RFLAGS_IF != 0
+}
+
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: 'a> {
+    guard: Option<MutexGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let were_enabled = interrupts_enabled();
+        unsafe { asm!("cli" :::: "volatile") };
+        IrqMutexGuard {
+            guard: Some(self.inner.lock()),
+            were_enabled: were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let were_enabled = interrupts_enabled();
+        unsafe { asm!("cli" :::: "volatile") };
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: Some(guard),
+                were_enabled: were_enabled,
+            }),
+            None => {
+                if were_enabled {
+                    unsafe { asm!("sti" :::: "volatile") };
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlock before interrupts can come in again
+        self.guard.take();
+        if self.were_enabled {
+            unsafe { asm!("sti" :::: "volatile") };
+        }
+    }
+}
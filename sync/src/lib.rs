@@ -0,0 +1,11 @@
+#![no_std]
+#![feature(asm)]
+#![feature(const_fn)]
+
+extern crate spin;
+
+mod irq_mutex;
+mod workqueue;
+
+pub use irq_mutex::{IrqMutex, IrqMutexGuard};
+pub use workqueue::{defer, has_pending, run_pending, Work};
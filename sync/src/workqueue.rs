@@ -0,0 +1,85 @@
+// Deferred work, a.k.a. bottom halves
+//
+// Interrupt handlers should only do what can't wait, e.g. fetch a byte before
+// the device overwrites it, and `defer` the rest. Deferred work runs later
+// with interrupts enabled: right after the IRQ has been acknowledged, or from
+// the idle loop. That keeps the time spent with interrupts disabled short no
+// matter how much a driver does with its data.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use irq_mutex::IrqMutex;
+
+// A work item is a function and a word of data, e.g. a scancode.
+pub type Work = fn(usize);
+
+const QUEUE_SIZE: usize = 64;
+
+struct WorkQueue {
+    items: [Option<(Work, usize)>; QUEUE_SIZE],
+    head:  usize,
+    len:   usize,
+}
+
+impl WorkQueue {
+    const fn new() -> WorkQueue {
+        WorkQueue { items: [None; QUEUE_SIZE], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, work: Work, data: usize) -> Result<(), &'static str> {
+        if self.len == QUEUE_SIZE {
+            return Err("Work queue is full.");
+        }
+        self.items[(self.head + self.len) % QUEUE_SIZE] = Some((work, data));
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<(Work, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        item
+    }
+}
+
+static QUEUE: IrqMutex<WorkQueue> = IrqMutex::new(WorkQueue::new());
+// Set while `run_pending` drains the queue, so that an interrupt arriving in
+// the middle doesn't start a second run on top of the first one.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Queue `work(data)` to run outside of interrupt context. Safe to call from
+// interrupt handlers.
+pub fn defer(work: Work, data: usize) -> Result<(), &'static str> {
+    QUEUE.lock().push(work, data)
+}
+
+pub fn has_pending() -> bool {
+    QUEUE.lock().len != 0
+}
+
+// Run queued work in FIFO order until the queue is empty. Interrupts must be
+// enabled by the caller, the queue lock is only held to take out one item.
+pub fn run_pending() {
+    loop {
+        if RUNNING.swap(true, Ordering::Acquire) {
+            return;    // an interrupted run further down the stack continues
+        }
+        loop {
+            let item = QUEUE.lock().pop();
+            match item {
+                Some((work, data)) => work(data),
+                None => break,
+            }
+        }
+        RUNNING.store(false, Ordering::Release);
+        // work deferred between the last pop and the store above was not run
+        // by the interrupt that queued it, since we were still running
+        if !has_pending() {
+            break;
+        }
+    }
+}
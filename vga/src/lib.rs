@@ -4,9 +4,9 @@
 #![feature(const_unique_new)]
 #![feature(ptr_internals)]
 
-extern crate spin;
+extern crate sync;
 
-use spin::Mutex;
+use sync::IrqMutex;
 use core::fmt::{self, Write};
 use core::ptr::Unique;
 
@@ -92,6 +92,18 @@ impl Writer {
         }
     }
 
+    // Erase the character left of the cursor and move back onto it.
+    pub fn clear_left(&mut self) {
+        if self.column_position == 0 {
+            return
+        }
+        self.column_position -= 1;
+        // can't use kprint here
+        // otherwist cause deadlock
+        self.write_byte(b' ');
+        self.column_position -= 1;
+    }
+
     fn buffer(&mut self) -> &mut Buffer {
         unsafe{ self.buffer.as_mut() }
     }
@@ -119,7 +131,7 @@ impl Writer {
     }
 }
 
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
+pub static WRITER: IrqMutex<Writer> = IrqMutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::White, Color::Blue),
     buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
@@ -146,18 +158,22 @@ pub fn clear_screen() {
     }
 }
 
+// An optional second console (e.g. a serial port) which receives
+// everything printed through `kprint!` in addition to the screen.
+static SINK: IrqMutex<Option<fn(fmt::Arguments)>> = IrqMutex::new(None);
+
+pub fn set_sink(sink: Option<fn(fmt::Arguments)>) {
+    *SINK.lock() = sink;
+}
+
 pub fn kprint(args: fmt::Arguments) {
     WRITER.lock().write_fmt(args).unwrap();
+    let sink = *SINK.lock();
+    if let Some(sink) = sink {
+        sink(args);
+    }
 }
 
 pub fn clear_left_once() {
-    let mut writer = WRITER.lock();
-    if writer.column_position <= 0 {
-        return
-    }
-    writer.column_position -= 1;
-    // can't use kprint here
-    // otherwist cause deadlock
-    writer.write_byte(' ' as u8);
-    writer.column_position -= 1;
+    WRITER.lock().clear_left();
 }